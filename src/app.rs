@@ -12,6 +12,8 @@ use serde::Deserialize;
 use serde::Serialize;
 use crate::json::*;
 use crate::defs;
+use crate::cancel::RunState;
+use crate::launcher::{CoreState, Event, LauncherCore, Task};
 use crate::patch::PlayGameConfig;
 use open;
 
@@ -38,6 +40,20 @@ pub struct ProcelioLauncher {
     image: String,
     #[serde(default)]
     graphics_api: GraphicsApi,
+    #[serde(default)]
+    launch_options: crate::game::LaunchSettings,
+    #[serde(default)]
+    retry_policy: crate::net::RetryPolicy,
+    #[serde(default)]
+    backend_override: crate::net::BackendOverride,
+    #[serde(default)]
+    bandwidth_limit: crate::throttle::BandwidthLimit,
+    #[serde(default)]
+    proxy: crate::net::ProxySettings,
+    /// Missing from old state, so it takes the struct default of 1 rather than 0.
+    download_connections: usize,
+    /// Largest download held in memory, in MB. Bigger ones go to a temp file.
+    download_memory_mb: u64,
     #[serde(skip)]
     refs: ResourceRefs,
     #[serde(skip)]
@@ -45,6 +61,11 @@ pub struct ProcelioLauncher {
     #[serde(skip)]
     licenses: bool,
     #[serde(skip)]
+    logs: bool,
+    /// The wrapper command as it is being typed, saved to `launch_options` once it checks out.
+    #[serde(skip)]
+    wrapper_draft: String,
+    #[serde(skip)]
     viewed_changelog: usize,
     #[serde(skip)]
     states: Ephemeral,
@@ -53,29 +74,36 @@ pub struct ProcelioLauncher {
 }
 
 pub struct Ephemeral {
-    config: LoadStatus<LauncherConfig>,
-    channel: LoadStatus<ConfigResponse>,
+    core: LauncherCore,
     image: LoadStatus<Vec<u8>>,
     launcher_redownload: LoadStatus<()>,
-    launching: LoadStatus<()>,
     uninstall: LoadStatus<()>,
-    new_version: LoadStatus<InstallManifest>,
-    error: Option<Box<anyhow::Error>>,
-    processing_status: Option<std::sync::Arc<std::sync::Mutex<(f32, String, Option<Box<anyhow::Error>>)>>>
+    verify: LoadStatus<VerifyReport>,
+    /// The install that can be started while the release server is unreachable.
+    offline: Option<InstallManifest>,
+    /// How the game last crashed, until the dialog is closed.
+    crash: Option<crate::game::GameExit>,
+    /// The `--backend` given on the command line, which wins over the saved override for the whole session.
+    cli_backend: Option<crate::net::BackendOverride>,
+    error: Option<Box<anyhow::Error>>
 }
 
 impl Ephemeral {
     pub fn new() -> Ephemeral {
         Ephemeral { 
-            config: LoadStatus::AppLoad, 
-            channel: LoadStatus::AppLoad,
+            core: {
+                let mut core = LauncherCore::new(Arc::new(crate::backend::HttpBackend), "prod", defs::AUTO_CDN);
+                core.set_cache(crate::cache::Cache::user());
+                core
+            },
             image: LoadStatus::AppLoad,
             launcher_redownload: LoadStatus::AppLoad,
             uninstall: LoadStatus::AppLoad,
-            launching: LoadStatus::AppLoad,
-            new_version: LoadStatus::AppLoad,
-            error: None, 
-            processing_status: None }
+            verify: LoadStatus::AppLoad,
+            offline: None,
+            crash: None,
+            cli_backend: None,
+            error: None }
     }
 
     pub fn ok_to_play(&self) -> bool {
@@ -83,13 +111,7 @@ impl Ephemeral {
             LoadStatus::AppLoad => true,
             _ => false
         };
-
-        let channel = match self.channel {
-            LoadStatus::Loaded(_) => true,
-            _ => false
-        };
-        let game = self.processing_status.is_none();
-        launcher && game && channel
+        launcher && self.core.state() == CoreState::Ready
     }
 }
 
@@ -197,10 +219,19 @@ impl Default for ProcelioLauncher {
             install_dir: None,
             image: "none".to_owned(),
             channel: "prod".to_owned(),
-            cdn: "nyc3".to_owned(),
+            cdn: defs::AUTO_CDN.to_owned(),
             graphics_api: GraphicsApi::Default,
+            launch_options: crate::game::LaunchSettings::default(),
+            retry_policy: crate::net::RetryPolicy::default(),
+            backend_override: crate::net::BackendOverride::default(),
+            bandwidth_limit: crate::throttle::BandwidthLimit::default(),
+            proxy: crate::net::ProxySettings::default(),
+            download_connections: 1,
+            download_memory_mb: crate::files::DEFAULT_MEMORY_LIMIT / 1_048_576,
             settings: false,
             licenses: false,
+            logs: false,
+            wrapper_draft: String::new(),
             viewed_changelog: 0,
             refs: ResourceRefs::new(),
             states: Ephemeral::new()
@@ -211,7 +242,7 @@ impl Default for ProcelioLauncher {
 impl ProcelioLauncher {
     fn redownload_internal(cdn: String) -> Result<(), anyhow::Error> {
         let url = crate::net::get_launcher_url(&cdn, defs::launcher_name())?;
-        let file = crate::net::download_file(None, &url, None)?;
+        let file = crate::net::download_file(None, &url, None, None, &crate::progress::Progress::default(), None)?;
         let mut data = Vec::new();
         file.as_reader().read_to_end(&mut data)?;
     
@@ -233,28 +264,87 @@ impl ProcelioLauncher {
         });
     }
 
-    fn gather_args(&self) -> Option<PlayGameConfig> {
-        if let LoadStatus::Loaded(t) = &self.states.channel {
-            let graphics_arg = match self.graphics_api {
-                GraphicsApi::Default => None,
-                GraphicsApi::Vulkan => Some("-force-vulkan"),
-                GraphicsApi::Dx12 => Some("-force-d3d12"),
-                GraphicsApi::Dx11 => Some("-force-d3d11")
-            };
+    /// The graphics API flag, then the user's launch arguments for `channel`.
+    fn extra_args(&self, channel: &str) -> Result<Vec<String>, anyhow::Error> {
+        let graphics_arg = match self.graphics_api {
+            GraphicsApi::Default => None,
+            GraphicsApi::Vulkan => Some("-force-vulkan"),
+            GraphicsApi::Dx12 => Some("-force-d3d12"),
+            GraphicsApi::Dx11 => Some("-force-d3d11")
+        };
+        let mut args: Vec<String> = graphics_arg.map(|x| x.to_owned()).into_iter().collect();
+        args.extend(self.launch_options.for_channel(channel).args()?);
+        Ok(args)
+    }
 
-            let mut args = t.args.clone();
-            if let Some(graphic) = graphics_arg {
-                args.push(graphic.to_owned());
+    /// The saved wrapper command. Only Linux has the tools it is meant for.
+    fn wrapper(&self) -> String {
+        match cfg!(target_os = "linux") {
+            true => self.launch_options.wrapper.clone(),
+            false => String::new()
+        }
+    }
+
+    fn gather_args(&self) -> Result<Option<PlayGameConfig>, anyhow::Error> {
+        let channel = self.states.core.channel();
+        let env = self.launch_options.for_channel(channel).env()?;
+        let config = self.states.core.play_config(&self.extra_args(channel)?, &env);
+        Ok(config.map(|x| PlayGameConfig { wrapper: self.wrapper(), ..x }))
+    }
+
+    /// Edits the launch options for every channel, or the selected channel's own if it has them,
+    /// and shows the command line they make.
+    fn launch_options_ui(&mut self, ui: &mut egui::Ui) {
+        let channel = self.channel.clone();
+        let mut own = self.launch_options.channels.contains_key(&channel);
+        if ui.checkbox(&mut own, format!("Separate options for {channel}")).changed() {
+            if own {
+                self.launch_options.channels.insert(channel.clone(), self.launch_options.all.clone());
+            } else {
+                self.launch_options.channels.remove(&channel);
+            }
+        }
+        let options = match self.launch_options.channels.get_mut(&channel) {
+            Some(o) => o,
+            None => &mut self.launch_options.all
+        };
+        ui.horizontal(|ui| {
+            ui.label("Arguments: ");
+            ui.text_edit_singleline(&mut options.args).on_hover_text("Passed to the game after the channel's own, e.g. -screen-fullscreen 0");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Environment: ");
+            ui.text_edit_singleline(&mut options.env).on_hover_text("KEY=VALUE pairs separated by spaces. Quote values with spaces");
+        });
+        if cfg!(target_os = "linux") {
+            ui.horizontal(|ui| {
+                ui.label("Wrapper: ");
+                ui.text_edit_singleline(&mut self.wrapper_draft)
+                    .on_hover_text("Run the game through another command, e.g. gamemoderun %command% or env FOO=1 mangohud %command%");
+                if ui.button("Apply").clicked() {
+                    match crate::game::wrap(&self.wrapper_draft, "game", &[]) {
+                        Ok(_) => self.launch_options.wrapper = self.wrapper_draft.clone(),
+                        Err(e) => self.states.error = Some(Box::new(e))
+                    }
+                }
+            });
+            if self.wrapper_draft != self.launch_options.wrapper {
+                ui.label("Not applied yet");
             }
+        }
 
-            Some(PlayGameConfig {
-                cdn: self.cdn.clone(),
-                channel: self.channel.clone(),
-                latest_build: t.newest_release_name.clone(),
-                args
-            })
-        } else {
-            None
+        match self.gather_args() {
+            Ok(Some(config)) => {
+                let installed = self.install_dir.as_ref().and_then(|x| crate::patch::get_installed_version(x).ok().flatten());
+                let program = installed.map(|x| x.exec).unwrap_or_else(|| "Procelio".to_owned());
+                ui.label("Command line:");
+                match crate::game::command_line(&program, &config) {
+                    Ok(line) => { ui.code(line); },
+                    Err(e) => { ui.colored_label(egui::Color32::RED, e.to_string()); }
+                }
+            },
+            Ok(None) => { ui.label("Loading channel data…"); },
+            Err(e) => { ui.colored_label(egui::Color32::RED, e.to_string()); }
         }
     }
 
@@ -266,49 +356,88 @@ impl ProcelioLauncher {
     }
 
     fn check_states(&mut self, ctx: &egui::Context, _frame: &eframe::Frame) -> bool {
-        if let LoadStatus::Pending(recv) = &mut self.states.config {
-            if let Ok(a) = recv.try_recv() {
-                match a {
-                    Ok(cfg) => {
-                        if !cfg.channels.contains(&self.channel) {
-                            self.channel = "prod".to_owned();
-                        }
-                        if !cfg.cdn_regions.contains(&self.cdn) {
-                            self.cdn = "nyc3".to_owned();
-                        }
-                        if cfg.metadata.version != defs::version() {
-                            self.states.launcher_redownload = LoadStatus::AwaitingApproval;
-                        }
-                        
-                        if self.image != cfg.metadata.bg_image {
-                            let (ss, rr) = std::sync::mpsc::channel();
-                            self.states.image = LoadStatus::Pending(rr);
-                            crate::net::get_image(self.image.clone(), cfg.metadata.bg_image.clone(), ss);
-                            self.image = cfg.metadata.bg_image.clone();
-                        }
+        for event in self.states.core.poll() {
+            match event {
+                Event::ConfigLoaded => {
+                    let cfg = self.states.core.config().unwrap();
+                    if cfg.metadata.version != defs::version() {
+                        self.states.launcher_redownload = LoadStatus::AwaitingApproval;
+                    }
 
-                        self.states.config = LoadStatus::Loaded(cfg);
+                    if self.image != cfg.metadata.bg_image {
+                        let (ss, rr) = std::sync::mpsc::channel();
+                        self.states.image = LoadStatus::Pending(rr);
+                        crate::net::get_image(self.states.core.best_cdn(), self.image.clone(), cfg.metadata.bg_image.clone(), ss);
+                        self.image = cfg.metadata.bg_image.clone();
+                    }
+                },
+                Event::Verified(report) => {
+                    self.states.verify = LoadStatus::Loaded(report);
+                },
+                Event::Failed(e) => {
+                    self.states.error = Some(Box::new(e))
+                },
+                Event::GameExited(exit) => {
+                    if exit.crashed() {
+                        self.states.crash = Some(exit);
+                    }
+                },
+                Event::Offline(e) => {
+                    // Without an install there is nothing to play, so it is just an error.
+                    match self.install_dir.as_ref().and_then(|x| crate::patch::get_installed_version(x).ok().flatten()) {
+                        Some(m) => self.states.offline = Some(m),
+                        None => self.states.error = Some(Box::new(e))
+                    }
+                },
+                Event::ChannelLoaded | Event::CdnProbed | Event::Updated(..) | Event::Uninstalled | Event::Cancelled(_) | Event::Progress(_) => { }
+            }
+        }
+        // The core falls back to prod/nyc3 if the saved ones are no longer offered.
+        self.channel = self.states.core.channel().to_owned();
+        self.cdn = self.states.core.cdn().to_owned();
+        match self.states.core.state() {
+            CoreState::Ready | CoreState::Offline => { },
+            // Only needs to notice the game exiting.
+            CoreState::Playing => ctx.request_repaint_after(std::time::Duration::from_secs(1)),
+            _ => ctx.request_repaint()
+        }
 
-                        let (s, r) = std::sync::mpsc::channel();
-                        self.states.channel = LoadStatus::Pending(r);
-                        ctx.request_repaint();
-                        crate::net::get_data(self.channel.clone(), s);
-                    },
-                    Err(e) => {
-                        self.states.error = Some(std::boxed::Box::new(e.into()))
+        if let Some(exit) = &self.states.crash {
+            let (mut close, mut export) = (false, false);
+            egui::Window::new("Procelio Crashed").show(ctx, |ui| {
+                ui.label(format!("The game stopped unexpectedly ({}).", exit.status));
+                if !exit.tail.is_empty() {
+                    ui.label("Last output:");
+                    egui::ScrollArea::vertical().max_height(240.).stick_to_bottom(true).show(ui, |ui| {
+                        ui.code(exit.tail.join("\n"));
+                    });
+                }
+                ui.horizontal(|ui| {
+                    if let Some(log) = &exit.log
+                        && ui.button("Open Log").clicked()
+                        && let Err(e) = open::that(log) {
+                        log::warn!("Unable to open {:?}: {e}", log);
                     }
-                };
+                    export = ui.button("Export Diagnostics").clicked();
+                    close = ui.button("OK").clicked();
+                });
+            });
+            if export {
+                self.export_diagnostics();
+            }
+            if close {
+                self.states.crash = None;
             }
         }
 
         if let LoadStatus::AwaitingApproval = &mut self.states.launcher_redownload {
             egui::Window::new("Approve Launcher Update?").show(ctx, |ui| {
-                if let LoadStatus::Loaded(x) = &self.states.config {
+                if let Some(x) = self.states.core.config() {
                     ui.label(format!("The launcher will download and update to version {}", x.metadata.version));
                     if ui.button("OK").clicked() {
                         let (s, r) = std::sync::mpsc::channel();
                         self.states.launcher_redownload = LoadStatus::Pending(r);
-                        ProcelioLauncher::redownload_launcher(self.cdn.clone(), s);
+                        ProcelioLauncher::redownload_launcher(self.states.core.best_cdn(), s);
                     }
                     if ui.button("Quit").clicked() {
                         ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
@@ -319,20 +448,6 @@ impl ProcelioLauncher {
             return true;
         }
 
-        if let LoadStatus::Pending(recv) = &mut self.states.channel {
-            ctx.request_repaint();
-            if let Ok(a) = recv.try_recv() {
-                match a {
-                    Ok(x) => {
-                        self.states.channel = LoadStatus::Loaded(x);
-                    },
-                    Err(e) => {
-                        self.states.error = Some(std::boxed::Box::new(e.into()))
-                    }
-                }
-            }
-        }
-
         if let LoadStatus::Pending(recv) = &mut self.states.image {
             if let Ok(a) = recv.try_recv() {
                 match a {
@@ -381,12 +496,10 @@ impl ProcelioLauncher {
                         ui.columns(4, |ui| {
                           //  ui[0].with_layout(egui::Layout::left_to_right(), |ui| {
                                 if ui[0].button(egui::RichText::new("Uninstall").color(egui::Color32::RED)).clicked() {
-                                    let mutex = std::sync::Arc::new(std::sync::Mutex::new((0., "Uninstalling".to_owned(), None)));
-                                    self.states.processing_status = Some(mutex.clone());
-        
-                                    let (send, recv) = std::sync::mpsc::channel();
-                                    self.states.uninstall = LoadStatus::Pending(recv);
-                                    crate::patch::uninstall(path.to_owned(), mutex, send);
+                                    self.states.uninstall = LoadStatus::AppLoad;
+                                    if let Err(e) = self.states.core.uninstall(path.to_owned()) {
+                                        self.states.error = Some(Box::new(e));
+                                    }
                                 }
                           //  });
 
@@ -403,34 +516,20 @@ impl ProcelioLauncher {
             }
         }
         
-        if let LoadStatus::Pending(recv) = &mut self.states.uninstall {
-            if let Ok(a) = recv.try_recv() {
-                match a {
-                    Ok(_) => {
-                        self.states.uninstall = LoadStatus::AppLoad;
-                        self.states.processing_status = None;
-                    },
-                    Err(e) => {
-                        self.states.error = Some(std::boxed::Box::new(e.into()))
-                    }
-                };
+        if let LoadStatus::Loaded(report) = &self.states.verify {
+            let mut close = false;
+            egui::Window::new("Verification Complete").show(ctx, |ui| {
+                ui.label(format!("Checked {} files, repaired {}.", report.checked, report.repaired.len()));
+                for file in &report.repaired {
+                    ui.code(file);
+                }
+                close = ui.button("OK").clicked();
+            });
+            if close {
+                self.states.verify = LoadStatus::AppLoad;
             }
         }
 
-        if let LoadStatus::Pending(recv) = &mut self.states.launching {
-            if let Ok(a) = recv.try_recv() {
-                match a {
-                    Ok(_) => {
-                        self.states.launching = LoadStatus::AppLoad;
-                        self.states.processing_status = None;
-                    },
-                    Err(e) => {
-                        self.states.error = Some(std::boxed::Box::new(e.into()))
-                    }
-                };
-            }
-        }
-        
         if self.readme_accepted != defs::CURRENT_README{
             egui::CentralPanel::default().show(ctx, |ui| {
                 let s = "THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS \"AS IS\" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.";
@@ -444,16 +543,6 @@ impl ProcelioLauncher {
             return true;
         }
 
-        if let LoadStatus::Pending(recv) = &mut self.states.new_version {
-            if let Ok(a) = recv.try_recv() {
-                match a {
-                    Ok(_) => { },
-                    Err(e) => {
-                        self.states.error = Some(std::boxed::Box::new(e.into()))
-                    }
-                };
-            }
-        }
         false
     }
 
@@ -542,18 +631,122 @@ impl ProcelioLauncher {
         });
     }
 
-    pub fn setup(&mut self, cc: &eframe::CreationContext<'_>) {
+    /// Edits a download limit in MB/s, where 0 means unlimited.
+    fn rate_ui(ui: &mut egui::Ui, rate: &mut u64) -> egui::Response {
+        const MB: f64 = 1024. * 1024.;
+        let mut mbps = *rate as f64 / MB;
+        let response = ui.add(egui::DragValue::new(&mut mbps).range(0. ..=1000.).speed(0.1).suffix(" MB/s"));
+        if response.changed() {
+            *rate = (mbps * MB) as u64;
+        }
+        response
+    }
+
+    /// The settings for `throttle::BandwidthLimit`. Returns whether anything changed.
+    fn bandwidth_limit_ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let limit = &mut self.bandwidth_limit;
+        let mut changed = ui.horizontal(|ui| {
+            ui.label("Download Limit: ");
+            ProcelioLauncher::rate_ui(ui, &mut limit.rate).on_hover_text("0 for no limit").changed()
+        }).inner;
+
+        let mut remove = None;
+        for (i, entry) in limit.schedule.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label("From");
+                changed |= ui.add(egui::DragValue::new(&mut entry.from).range(0..=23).suffix(":00")).changed();
+                ui.label("to");
+                changed |= ui.add(egui::DragValue::new(&mut entry.to).range(0..=23).suffix(":00")).changed();
+                changed |= ProcelioLauncher::rate_ui(ui, &mut entry.rate).changed();
+                if entry.from == entry.to {
+                    ui.label("(all day)");
+                }
+                if ui.button("Remove").clicked() {
+                    remove = Some(i);
+                }
+            });
+        }
+        if let Some(i) = remove {
+            limit.schedule.remove(i);
+            changed = true;
+        }
+        if ui.button("Add Scheduled Limit").clicked() {
+            limit.schedule.push(crate::throttle::ScheduledLimit { from: 9, to: 17, rate: limit.rate });
+            changed = true;
+        }
+        changed
+    }
+
+    /// Picks the release server: a command line override wins over the environment, which wins over settings.
+    fn apply_backend(&mut self) {
+        let settings = Some(self.backend_override.clone()).filter(|x| !x.url.trim().is_empty());
+        let backend = match self.states.cli_backend.clone().or_else(crate::net::BackendOverride::from_env).or(settings) {
+            Some(o) => crate::net::Backend::from_override(&o),
+            None => Ok(crate::net::Backend::production())
+        };
+        match backend {
+            Ok(b) => crate::net::set_backend(b),
+            Err(e) => self.states.error = Some(Box::new(e))
+        }
+    }
+
+    /// Asks where to save a diagnostics bundle, and writes it there.
+    fn export_diagnostics(&mut self) {
+        let name = format!("procelio-diagnostics-{}.zip", chrono::Local::now().format("%Y%m%d-%H%M%S"));
+        let Some(path) = rfd::FileDialog::new().set_file_name(name).add_filter("Zip", &["zip"]).save_file() else {
+            return;
+        };
+        let installed = self.install_dir.as_ref().and_then(|x| crate::patch::get_installed_version(x).ok().flatten());
+        let report = crate::diagnostics::Report {
+            summary: vec![
+                ("Channel".to_owned(), self.states.core.channel().to_owned()),
+                ("Download region".to_owned(), format!("{} (using {})", self.states.core.cdn(), self.states.core.best_cdn())),
+                ("Installed".to_owned(), installed.map_or("no".to_owned(), |x| format!("{} ({})", x.version, x.channel))),
+                ("Install dir".to_owned(), format!("{:?}", self.install_dir)),
+                ("Backend".to_owned(), crate::net::backend().url),
+                ("State".to_owned(), format!("{:?}", self.states.core.state()))
+            ],
+            settings: serde_json::to_value(&*self).unwrap_or_default(),
+            install_dir: self.install_dir.clone(),
+            error: self.states.error.as_ref().map(|x| format!("{:?}", x))
+                .or_else(|| self.states.crash.as_ref().map(|x| format!("Game crashed: {}", x.status)))
+        };
+        match crate::diagnostics::export(&report, &path) {
+            Ok(()) => log::info!("Diagnostics saved to {:?}", path),
+            Err(e) => self.states.error = Some(Box::new(e))
+        }
+    }
+
+    fn reload_config(&mut self) {
+        self.states.core.load_config();
+    }
+
+    pub fn setup(&mut self, cc: &eframe::CreationContext<'_>, backend: Option<crate::net::BackendOverride>) {
         let ctx: &egui::Context = &cc.egui_ctx;
         if let Some(storage) = cc.storage {
             *self = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default()
         }
+        crate::net::set_retry_policy(self.retry_policy.clone());
+        crate::throttle::set_limit(self.bandwidth_limit.clone());
+        crate::net::set_download_connections(self.download_connections);
+        crate::files::set_memory_limit(self.download_memory_mb * 1_048_576);
+        self.wrapper_draft = self.launch_options.wrapper.clone();
+        if let Err(e) = crate::net::set_proxy(self.proxy.clone()) {
+            self.states.error = Some(Box::new(e.into()));
+        }
+        self.states.cli_backend = backend;
+        self.apply_backend();
 
-        let (s, r) = std::sync::mpsc::channel();
-        self.states.config = LoadStatus::Pending(r);
-        crate::net::get_config(s);
+        self.states.core.select_channel(&self.channel);
+        self.states.core.select_cdn(&self.cdn);
+        self.reload_config();
         if let None = self.install_dir {
             self.settings = true;
         }
+        // The proxy password is not saved, so ask for it again.
+        if !self.proxy.username.is_empty() {
+            self.settings = true;
+        }
         if let Err(e) = crate::patch::delete_old_launcher() {
             self.states.error = Some(Box::new(e));
         }
@@ -583,6 +776,11 @@ impl eframe::App for ProcelioLauncher {
         eframe::set_value(storage, eframe::APP_KEY, self);
     }
 
+    /// Stops any download or patch so it does not leave partial files behind.
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.states.core.shutdown();
+    }
+
     /// Called each time the UI needs repainting, which may be many times per second.
     /// Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
@@ -601,7 +799,11 @@ impl eframe::App for ProcelioLauncher {
                 self.refs.get_background(Some(x), ctx)
             },
             _ => {
-                let img = crate::net::load_image(self.image.clone(), self.image.clone());
+                // Only what is already on disk. A new image is fetched on its own thread once the config is in.
+                let img = match self.refs.background {
+                    Some(_) => None,
+                    None => crate::net::saved_image()
+                };
                 self.refs.get_background(img.as_ref(), ctx)
             }
         };
@@ -638,8 +840,12 @@ impl eframe::App for ProcelioLauncher {
                         .inner_margin(Margin { left: 10, right: 10, top: 10, bottom: 10 })
                         .show(&mut ui[1], |ui| {
                         ui.with_layout(egui::Layout::top_down(egui::Align::RIGHT), |ui| {
+                            let backend = crate::net::backend();
+                            if !backend.production {
+                                ui.label(egui::RichText::new(format!("NON-PRODUCTION BACKEND: {}", backend.url)).size(12.0).strong().color(egui::Color32::from_rgb(255, 117, 0)));
+                            }
                             ui.label(egui::RichText::new("DAILY MESSAGE").size(18.0).strong().color(col2));
-                            if let LoadStatus::Loaded(x) = &self.states.config {
+                            if let Some(x) = self.states.core.config() {
                                 ui.label(egui::RichText::new(&x.metadata.message_of_the_day).size(18.0).strong().color(col2));
                                 ui.label(egui::RichText::new(&x.metadata.motd_author).size(9.0).color(col2));
                             }
@@ -669,40 +875,96 @@ impl eframe::App for ProcelioLauncher {
                     let launch = egui::widgets::Button::image(tex);
                     ui.with_layout(egui::Layout::from_main_dir_and_cross_align(egui::Direction::BottomUp, egui::Align::RIGHT), |ui| {
                       //  ui.add_space(1.0);
-                        if ui.add_sized(size, launch).clicked() && self.states.ok_to_play() {
+                        let playing = self.states.core.state() == CoreState::Playing;
+                        let play = ui.add_enabled_ui(!playing, |ui| ui.add_sized(size, launch)).inner;
+                        if playing {
+                            ui.label(egui::RichText::new("Playing…").color(egui::Color32::from_rgb(225, 225, 225)));
+                        }
+                        if play.clicked() && self.states.ok_to_play() {
                             if let Some(s1) = &self.install_dir {
-                                if let LoadStatus::Loaded(_) = &self.states.config {
-                                    let (s, r) = std::sync::mpsc::channel();
-                                    let (vs, vr) = std::sync::mpsc::channel();
-                                    self.states.launching = LoadStatus::Pending(r);
-                                    self.states.new_version = LoadStatus::Pending(vr);
-
-                                    let mutex = std::sync::Arc::new(std::sync::Mutex::new((0., "pending".to_owned(), None)));
-                                    self.states.processing_status = Some(mutex.clone());
-
-                                    if let Some(c) = self.gather_args() {
-                                        crate::patch::play_clicked(s1.to_path_buf(), c, mutex, s, vs);
-
-                                    }
+                                match self.gather_args() {
+                                    Ok(Some(c)) => if let Err(e) = self.states.core.launch(s1.to_path_buf(), c) {
+                                        self.states.error = Some(Box::new(e));
+                                    },
+                                    Ok(None) => { },
+                                    Err(e) => self.states.error = Some(Box::new(e))
                                 }
                             }
                         }
                     });
 
-                    if let Some(s) = &self.states.processing_status {
+                    if self.states.core.state() == CoreState::Offline
+                        && let Some(manifest) = self.states.offline.clone() {
+                        ui.with_layout(egui::Layout::from_main_dir_and_cross_align(egui::Direction::BottomUp, egui::Align::LEFT), |ui| {
+                            egui::Frame::NONE.inner_margin(Margin::symmetric(12, 0)).show(ui, |ui| {
+                                ui.add_space(4.);
+                                ui.horizontal(|ui| {
+                                    if ui.button(format!("Play offline (version {}, channel {})", manifest.version, manifest.channel)).clicked()
+                                        && let Some(dir) = &self.install_dir {
+                                        let env = self.launch_options.for_channel(&manifest.channel).env();
+                                        let wrapper = self.wrapper();
+                                        let launched = self.extra_args(&manifest.channel).and_then(|args| {
+                                            let config = PlayGameConfig { wrapper, ..self.states.core.offline_play_config(&manifest, &args, &env?) };
+                                            self.states.core.launch_offline(dir.to_path_buf(), config)
+                                        });
+                                        if let Err(e) = launched {
+                                            self.states.error = Some(Box::new(e));
+                                        }
+                                    }
+                                    if ui.button("Retry").clicked() {
+                                        self.reload_config();
+                                    }
+                                });
+                                ui.add_space(4.);
+                                let saved = self.states.core.fetched().map(|x| format!(", showing news from {}", x.format("%Y-%m-%d %H:%M")));
+                                ui.label(format!("Unable to reach the Procelio servers{}", saved.unwrap_or_default()));
+                            });
+                        });
+                    }
+
+                    if let Some(progress) = self.states.core.progress().cloned() {
                         ctx.request_repaint();
-                        let mut state = s.lock().unwrap();
-                        if state.2.is_some() {
-                            self.states.error = std::mem::take(&mut state.2);
-                        }
                         ui.with_layout(egui::Layout::from_main_dir_and_cross_align(egui::Direction::BottomUp, egui::Align::LEFT), |ui| {
                             egui::Frame::NONE.inner_margin(Margin::symmetric(12, 0)).show(ui, |ui| {
                                 ui.add_space(4.);
 
-                                ui.add(egui::widgets::Label::new(&state.1));
+                                let run_state = self.states.core.run_state();
+                                let label = match run_state {
+                                    Some(RunState::Paused) => format!("Paused: {progress}"),
+                                    Some(RunState::Cancelled) if progress.phase == crate::progress::Phase::Patching => "Cancelling after this patch...".to_owned(),
+                                    Some(RunState::Cancelled) => "Cancelling...".to_owned(),
+                                    _ => crate::net::retry_notice().map(|x| x.to_string()).unwrap_or_else(|| progress.to_string())
+                                };
+                                ui.add(egui::widgets::Label::new(label));
+                                if let Some(transfer) = progress.transfer() {
+                                    ui.add(egui::widgets::Label::new(transfer));
+                                }
 
                                 ui.add_space(4.);
-                                ui.add(egui::widgets::ProgressBar::new(state.0).show_percentage().animate(true));
+                                ui.add(egui::widgets::ProgressBar::new(progress.fraction()).show_percentage().animate(run_state == Some(RunState::Running)));
+
+                                if let (Some(part), Some(overall)) = (progress.part, progress.overall()) {
+                                    ui.add_space(4.);
+                                    ui.add(egui::widgets::Label::new(overall));
+                                    ui.add(egui::widgets::ProgressBar::new(part.fraction()).show_percentage());
+                                }
+
+                                if self.states.core.state() != CoreState::Busy(Task::Uninstall) {
+                                    ui.add_space(4.);
+                                    ui.horizontal(|ui| {
+                                        let cancelling = run_state == Some(RunState::Cancelled);
+                                        if run_state == Some(RunState::Paused) {
+                                            if ui.button("Resume").clicked() {
+                                                self.states.core.resume();
+                                            }
+                                        } else if ui.add_enabled(!cancelling, egui::Button::new("Pause")).clicked() {
+                                            self.states.core.pause();
+                                        }
+                                        if ui.add_enabled(!cancelling, egui::Button::new("Cancel")).clicked() {
+                                            self.states.core.cancel();
+                                        }
+                                    });
+                                }
 
                                 egui::warn_if_debug_build(ui);
                             });
@@ -720,6 +982,11 @@ impl eframe::App for ProcelioLauncher {
                     ui.label(egui::RichText::new("Built with ").color(col));
                     ui.hyperlink_to(egui::RichText::new("egui").strong(), "https://github.com/emilk/egui");
                 });
+                if self.states.core.progress().is_none()
+                    && let Some(notice) = crate::net::retry_notice() {
+                    ctx.request_repaint();
+                    ui.label(egui::RichText::new(notice.to_string()).color(egui::Color32::from_rgb(225, 225, 225)));
+                }
             });
         });
         
@@ -743,7 +1010,7 @@ impl eframe::App for ProcelioLauncher {
 
                 egui::Frame::NONE
                 .show(ui, |ui| {
-                    if let LoadStatus::Loaded(x) = &self.states.channel {
+                    if let Some(x) = self.states.core.channel_data() {
                         ui.with_layout(egui::Layout::left_to_right(egui::Align::Center), |ui| {
 
                             const WIDTH: usize = 3;
@@ -797,7 +1064,7 @@ impl eframe::App for ProcelioLauncher {
                 egui::ComboBox::from_label("Release Channel")
                     .selected_text(format!("{}", &self.channel))
                     .show_ui(ui, |ui| {
-                        if let LoadStatus::Loaded(s) = &self.states.config {
+                        if let Some(s) = self.states.core.config() {
                             let pre = self.channel.clone();
 
                             s.channels.iter().for_each(|x| {
@@ -805,33 +1072,42 @@ impl eframe::App for ProcelioLauncher {
                             });
 
                             if self.channel != pre /* Is there a better "wasModified" in egui? */ {
-                                let (s, r) = std::sync::mpsc::channel();
-                                self.states.channel = LoadStatus::Pending(r);
-                                crate::net::get_data(self.channel.clone(), s);
+                                self.states.core.select_channel(&self.channel);
                             }
                         }
                     });
 
+                let region = match self.cdn == defs::AUTO_CDN {
+                    true => format!("{} ({})", defs::AUTO_CDN, self.states.core.best_cdn()),
+                    false => self.cdn.clone()
+                };
                 egui::ComboBox::from_label("Download Region")
-                    .selected_text(format!("{}", &self.cdn))
+                    .selected_text(region)
                     .show_ui(ui, |ui| {
-                        if let LoadStatus::Loaded(s) = &self.states.config {
+                        if let Some(s) = self.states.core.config() {
+                            let pre = self.cdn.clone();
+                            ui.selectable_value(&mut self.cdn, defs::AUTO_CDN.to_owned(), defs::AUTO_CDN);
                             s.cdn_regions.iter().for_each(|x| {
                                 ui.selectable_value(&mut self.cdn, x.to_owned(), format!("{}", x));
                             });
+                            if self.cdn != pre {
+                                self.states.core.select_cdn(&self.cdn);
+                            }
                         }
                     }); 
 
                 egui::ComboBox::from_label("Graphics API")
                     .selected_text(format!("{:?}", &self.graphics_api))
                     .show_ui(ui, |ui: &mut egui::Ui| {
-                        if let LoadStatus::Loaded(_) = &self.states.config {
+                        if self.states.core.config().is_some() {
                             ui.selectable_value(&mut self.graphics_api, GraphicsApi::Default, "Default");
                             ui.selectable_value(&mut self.graphics_api, GraphicsApi::Vulkan, "Vulkan");
                             ui.selectable_value(&mut self.graphics_api, GraphicsApi::Dx12, "DX12");
                             ui.selectable_value(&mut self.graphics_api, GraphicsApi::Dx11, "DX11");
                         }
                     }); 
+
+                ui.collapsing("Launch Options", |ui| self.launch_options_ui(ui));
                     
                 ui.horizontal(|ui| {
                     if ui.button("Install To: ").clicked() {
@@ -844,10 +1120,110 @@ impl eframe::App for ProcelioLauncher {
                     ui.code(format!("{}", self.install_dir.as_ref().map(|x|x.as_os_str().to_string_lossy().into_owned()).unwrap_or("".to_owned())));
                 });
 
+                let mut timeout = self.retry_policy.timeout.as_secs();
+                let retries = ui.add(egui::Slider::new(&mut self.retry_policy.attempts, 1..=10).text("Network Attempts"));
+                let timeout_changed = ui.add(egui::Slider::new(&mut timeout, 5..=120).text("Network Timeout (s)"));
+                if retries.changed() || timeout_changed.changed() {
+                    self.retry_policy.timeout = std::time::Duration::from_secs(timeout);
+                    crate::net::set_retry_policy(self.retry_policy.clone());
+                }
+                if ui.add(egui::Slider::new(&mut self.download_connections, 1..=crate::net::MAX_CONNECTIONS).text("Download Connections"))
+                    .on_hover_text("Split large downloads across this many connections. Can help over long distances")
+                    .changed() {
+                    crate::net::set_download_connections(self.download_connections);
+                }
+                if ui.add(egui::Slider::new(&mut self.download_memory_mb, 0..=1024).text("Download Memory (MB)"))
+                    .on_hover_text("Downloads bigger than this are written to a temp file instead of held in memory")
+                    .changed() {
+                    crate::files::set_memory_limit(self.download_memory_mb * 1_048_576);
+                }
+
+                ui.collapsing("Bandwidth Limit", |ui| {
+                    if self.bandwidth_limit_ui(ui) {
+                        crate::throttle::set_limit(self.bandwidth_limit.clone());
+                    }
+                });
+
+                ui.collapsing("Proxy", |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Proxy URL: ");
+                        ui.text_edit_singleline(&mut self.proxy.url).on_hover_text("http://, https:// or socks5://. Leave empty to use the system proxy");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Username: ");
+                        ui.text_edit_singleline(&mut self.proxy.username);
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Password: ");
+                        ui.add(egui::TextEdit::singleline(&mut self.proxy.password).password(true))
+                            .on_hover_text("Not saved. Enter it again each time the launcher starts");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("No Proxy: ");
+                        ui.text_edit_singleline(&mut self.proxy.no_proxy).on_hover_text("Comma-separated hosts to connect to directly");
+                    });
+                    let mut remove = None;
+                    for (i, cert) in self.proxy.ca_certs.iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            ui.code(cert.to_string_lossy());
+                            if ui.button("Remove").clicked() {
+                                remove = Some(i);
+                            }
+                        });
+                    }
+                    if let Some(i) = remove {
+                        self.proxy.ca_certs.remove(i);
+                    }
+                    if ui.button("Add CA Certificate").clicked()
+                        && let Some(path) = rfd::FileDialog::new().add_filter("PEM", &["pem", "crt"]).pick_file() {
+                        self.proxy.ca_certs.push(path);
+                    }
+                    if ui.button("Apply").clicked() {
+                        match crate::net::set_proxy(self.proxy.clone()) {
+                            Ok(()) => self.reload_config(),
+                            Err(e) => self.states.error = Some(Box::new(e.into()))
+                        }
+                    }
+                });
+
+                ui.collapsing("Release Server", |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Backend Override: ");
+                        ui.text_edit_singleline(&mut self.backend_override.url).on_hover_text("Leave empty to use the production server");
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("Pinned Certificate: ").on_hover_text("Required unless the server runs on this machine").clicked() {
+                            self.backend_override.cert = rfd::FileDialog::new().add_filter("PEM", &["pem", "crt"]).pick_file();
+                        }
+                        ui.code(self.backend_override.cert.as_ref().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default());
+                    });
+                    if let Some(cli) = &self.states.cli_backend {
+                        ui.label(format!("Using {} from the command line until the launcher is restarted", cli.url));
+                    }
+                    if ui.button("Apply").clicked() {
+                        self.apply_backend();
+                        self.reload_config();
+                    }
+                });
+
                 if ui.button("View Licenses").clicked() {
                     self.licenses = true;
                 }
 
+                if ui.button("Show Logs").clicked() {
+                    self.logs = true;
+                }
+
+                if ui.button("Export Diagnostics").on_hover_text("Save logs and settings to a zip to attach to a bug report").clicked() {
+                    self.export_diagnostics();
+                }
+
+                if ui.button("Verify Files").clicked() && self.states.ok_to_play()
+                    && let Some(path) = &self.install_dir
+                    && let Err(e) = self.states.core.verify(path.to_owned()) {
+                    self.states.error = Some(Box::new(e));
+                }
+
                 ui.label("");
                 if ui.button(egui::RichText::new("Uninstall Procelio").color(egui::Color32::RED)).clicked() && self.states.ok_to_play() {
                     self.states.uninstall = LoadStatus::AwaitingApproval;
@@ -871,13 +1247,38 @@ impl eframe::App for ProcelioLauncher {
             });
         }
 
+        if self.logs {
+            // New lines arrive without any input to trigger a repaint.
+            ctx.request_repaint_after(std::time::Duration::from_secs(1));
+            egui::Window::new("// Launcher Log").show(ctx, |ui| {
+                egui::ScrollArea::both().max_height(320.).stick_to_bottom(true).show(ui, |ui| {
+                    ui.code(crate::logging::recent().join("\n"));
+                });
+                ui.horizontal(|ui| {
+                    if let Some(path) = crate::logging::log_path()
+                        && ui.button("Open Log File").clicked()
+                        && let Err(e) = open::that(&path) {
+                        log::warn!("Unable to open {:?}: {e}", path);
+                    }
+                    if ui.button("Close").clicked() {
+                        self.logs = false;
+                    }
+                });
+            });
+        }
+
         if let Some(x) = self.states.error.as_ref().map(|x| format!("{:?}", x)) {
             egui::Window::new("error-window").show(ctx, |ui| {
                 ui.label("Error:");
                 ui.label(format!("{:?}",x));
-                if ui.button("OK").clicked() {
-                    self.states.error = None;
-                }
+                ui.horizontal(|ui| {
+                    if ui.button("OK").clicked() {
+                        self.states.error = None;
+                    }
+                    if ui.button("Export Diagnostics").clicked() {
+                        self.export_diagnostics();
+                    }
+                });
             });
         }
     }
@@ -0,0 +1,391 @@
+//! The release server as seen by the install flow, so the flow can run against an in-memory fake.
+
+use std::path::Path;
+use std::time::Duration;
+use crate::cancel::CancelToken;
+use crate::files::{BufReadSeek, LoadedFileSource};
+use crate::json::{ConfigResponse, FileManifest, LauncherConfig, Release, UpgradePath};
+use crate::net::{Fetched, Validator, ZipListing};
+use crate::progress::Progress;
+
+pub trait ReleaseBackend: Send + Sync {
+    /// The launcher config, unless it is unchanged since `since`. Fails if the killswitch is engaged.
+    fn config(&self, since: Option<&Validator>) -> Result<Fetched<LauncherConfig>, anyhow::Error>;
+    fn channel_data(&self, channel: &str, since: Option<&Validator>) -> Result<Fetched<ConfigResponse>, anyhow::Error>;
+    fn latest_build(&self, channel: &str) -> Result<String, anyhow::Error>;
+    fn upgrade_route(&self, from_channel: &str, to_channel: &str, from_release: &str) -> Result<UpgradePath, anyhow::Error>;
+    /// The size and digest of release `name`, so a full download can be checked before it is extracted.
+    fn release(&self, channel: &str, name: &str) -> Result<Release, anyhow::Error>;
+    fn release_url(&self, cdn: &str, channel: &str, name: &str) -> Result<String, anyhow::Error>;
+    fn patch_url(&self, cdn: &str, channel: &str, name: &str) -> Result<String, anyhow::Error>;
+    fn file_manifest(&self, cdn: &str, channel: &str, name: &str) -> Result<FileManifest, anyhow::Error>;
+    /// How long `cdn` takes to answer. Used to rank regions when the user picks "Auto".
+    fn probe_cdn(&self, cdn: &str, channel: &str) -> Result<Duration, anyhow::Error>;
+    /// Downloads `url`, resuming from `partial` and checking `sha256` when given. See `net::download_file`.
+    fn download(
+        &self,
+        size: Option<u64>,
+        url: &str,
+        partial: Option<&Path>,
+        sha256: Option<&str>,
+        progress: &Progress,
+        cancel: Option<&CancelToken>) -> Result<LoadedFileSource, anyhow::Error>;
+    /// What the zip at `url` holds, read without downloading it. `None` if that cannot be known up front.
+    fn zip_listing(&self, url: &str) -> Result<Option<ZipListing>, anyhow::Error>;
+    /// Applies a downloaded patch archive to the install at `dir`, reporting progress as a fraction to `cb`.
+    fn apply_patch(&self, dir: &Path, archive: Box<dyn BufReadSeek>, cb: Option<&dyn Fn(f32, String)>) -> Result<(), anyhow::Error> {
+        let mut zip = zip::read::ZipArchive::new(archive)?;
+        proceliotool::tools::patch::from_zip(dir.to_owned(), &mut zip, cb)
+    }
+}
+
+/// The real release server, configured through `net::set_backend`.
+#[derive(Clone, Copy, Default)]
+pub struct HttpBackend;
+
+impl ReleaseBackend for HttpBackend {
+    fn config(&self, since: Option<&Validator>) -> Result<Fetched<LauncherConfig>, anyhow::Error> {
+        Ok(crate::net::load_config(since)?)
+    }
+
+    fn channel_data(&self, channel: &str, since: Option<&Validator>) -> Result<Fetched<ConfigResponse>, anyhow::Error> {
+        Ok(crate::net::load_channel_data(channel, since)?)
+    }
+
+    fn latest_build(&self, channel: &str) -> Result<String, anyhow::Error> {
+        Ok(crate::net::get_latest_build(channel)?.trim().to_owned())
+    }
+
+    fn upgrade_route(&self, from_channel: &str, to_channel: &str, from_release: &str) -> Result<UpgradePath, anyhow::Error> {
+        Ok(crate::net::get_update_path(from_channel, to_channel, from_release)?)
+    }
+
+    fn release(&self, channel: &str, name: &str) -> Result<Release, anyhow::Error> {
+        Ok(crate::net::get_release(channel, name)?)
+    }
+
+    fn release_url(&self, cdn: &str, channel: &str, name: &str) -> Result<String, anyhow::Error> {
+        Ok(crate::net::get_release_url(cdn, channel, name)?)
+    }
+
+    fn patch_url(&self, cdn: &str, channel: &str, name: &str) -> Result<String, anyhow::Error> {
+        Ok(crate::net::get_patch_url(cdn, channel, name)?)
+    }
+
+    fn file_manifest(&self, cdn: &str, channel: &str, name: &str) -> Result<FileManifest, anyhow::Error> {
+        crate::net::get_file_manifest(cdn, channel, name)
+    }
+
+    fn probe_cdn(&self, cdn: &str, channel: &str) -> Result<Duration, anyhow::Error> {
+        Ok(crate::net::probe_cdn(cdn, channel)?)
+    }
+
+    fn download(
+        &self,
+        size: Option<u64>,
+        url: &str,
+        partial: Option<&Path>,
+        sha256: Option<&str>,
+        progress: &Progress,
+        cancel: Option<&CancelToken>) -> Result<LoadedFileSource, anyhow::Error> {
+        crate::net::download_file(size, url, partial, sha256, progress, cancel)
+    }
+
+    fn zip_listing(&self, url: &str) -> Result<Option<ZipListing>, anyhow::Error> {
+        Ok(crate::net::zip_listing(url)?)
+    }
+}
+
+#[cfg(test)]
+pub use fake::FakeBackend;
+
+#[cfg(test)]
+mod fake {
+    use std::collections::{HashMap, HashSet};
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use sha2::{Digest, Sha256};
+    use crate::cancel::CancelToken;
+    use crate::files::{BufReadSeek, LoadedFileSource};
+    use crate::json::{ConfigResponse, FileManifest, LauncherConfig, LauncherMetadata, Release, UpgradePath};
+    use crate::net::{Fetched, NetError, Validator, ZipListing};
+    use crate::progress::Progress;
+    use super::ReleaseBackend;
+
+    #[derive(Default)]
+    struct FakeState {
+        config: Option<LauncherConfig>,
+        channels: HashMap<String, ConfigResponse>,
+        routes: HashMap<(String, String, String), UpgradePath>,
+        releases: HashMap<(String, String), Release>,
+        files: HashMap<String, Vec<u8>>,
+        manifests: HashMap<(String, String), FileManifest>,
+        latency: HashMap<String, Duration>,
+        failing: HashSet<String>,
+        offline: bool,
+        not_modified: usize,
+        downloads: Vec<String>,
+        unpacked: HashMap<String, u64>
+    }
+
+    impl FakeState {
+        /// Answers like a server whose `ETag` is a hash of the content.
+        fn fetched<T: serde::Serialize>(&mut self, data: T, since: Option<&Validator>) -> Fetched<T> {
+            let etag = format!("\"{}\"", hex::encode(Sha256::digest(serde_json::to_vec(&data).unwrap())));
+            if since.is_some_and(|x| x.etag.as_deref() == Some(etag.as_str())) {
+                self.not_modified += 1;
+                return Fetched::NotModified;
+            }
+            Fetched::Modified(data, Validator { etag: Some(etag), last_modified: None })
+        }
+    }
+
+    /// An in-memory release server for tests. Assets live at `fake://{cdn}/{kind}/{channel}/{name}`.
+    #[derive(Default)]
+    pub struct FakeBackend {
+        state: Mutex<FakeState>
+    }
+
+    impl FakeBackend {
+        pub fn new(channels: &[&str], cdn_regions: &[&str]) -> FakeBackend {
+            let backend = FakeBackend::default();
+            backend.state.lock().unwrap().config = Some(LauncherConfig {
+                channels: channels.iter().map(|x| x.to_string()).collect(),
+                metadata: LauncherMetadata {
+                    version: crate::defs::version().to_owned(),
+                    website_url: String::new(),
+                    message_of_the_day: String::new(),
+                    motd_author: String::new(),
+                    bg_image: String::new()
+                },
+                cdn_regions: cdn_regions.iter().map(|x| x.to_string()).collect()
+            });
+            backend
+        }
+
+        fn url(cdn: &str, kind: &str, channel: &str, name: &str) -> String {
+            format!("fake://{cdn}/{kind}/{channel}/{name}")
+        }
+
+        /// Publishes `newest` as the latest release of `channel`.
+        pub fn set_channel(&self, channel: &str, newest: &str, args: &[&str]) {
+            self.state.lock().unwrap().channels.insert(channel.to_owned(), ConfigResponse {
+                newest_release_name: newest.to_owned(),
+                args: args.iter().map(|x| x.to_string()).collect(),
+                changelog: Vec::new()
+            });
+        }
+
+        pub fn set_route(&self, from_channel: &str, to_channel: &str, from_release: &str, route: UpgradePath) {
+            self.state.lock().unwrap().routes.insert((from_channel.to_owned(), to_channel.to_owned(), from_release.to_owned()), route);
+        }
+
+        /// Publishes release `name` on `cdn`, described with its real size and digest.
+        pub fn add_release(&self, cdn: &str, channel: &str, name: &str, data: Vec<u8>) {
+            let mut state = self.state.lock().unwrap();
+            state.releases.insert((channel.to_owned(), name.to_owned()), Release {
+                channel: channel.to_owned(),
+                platform: "linux".to_owned(),
+                name: name.to_owned(),
+                download_size: data.len() as u64,
+                title: name.to_owned(),
+                description: String::new(),
+                changelog: String::new(),
+                sha256: Some(hex::encode(Sha256::digest(&data)))
+            });
+            state.files.insert(FakeBackend::url(cdn, "release", channel, name), data);
+        }
+
+        /// Replaces what the server says about a release, e.g. to advertise the wrong digest.
+        pub fn set_release(&self, release: Release) {
+            self.state.lock().unwrap().releases.insert((release.channel.clone(), release.name.clone()), release);
+        }
+
+        /// Forgets what the server says about a release, as a server without release records would.
+        pub fn remove_release(&self, channel: &str, name: &str) {
+            self.state.lock().unwrap().releases.remove(&(channel.to_owned(), name.to_owned()));
+        }
+
+        pub fn add_patch(&self, cdn: &str, channel: &str, name: &str, data: Vec<u8>) {
+            self.state.lock().unwrap().files.insert(FakeBackend::url(cdn, "patch", channel, name), data);
+        }
+
+        pub fn add_file_manifest(&self, channel: &str, name: &str, manifest: FileManifest) {
+            self.state.lock().unwrap().manifests.insert((channel.to_owned(), name.to_owned()), manifest);
+        }
+
+        /// Makes `cdn` answer probes after `latency`. Regions default to no latency at all.
+        pub fn set_latency(&self, cdn: &str, latency: Duration) {
+            self.state.lock().unwrap().latency.insert(cdn.to_owned(), latency);
+        }
+
+        /// Makes every download from `cdn` drop partway through.
+        pub fn fail_downloads(&self, cdn: &str) {
+            self.state.lock().unwrap().failing.insert(cdn.to_owned());
+        }
+
+        /// Makes the config and channel data unreachable, as if the network was down.
+        pub fn set_offline(&self, offline: bool) {
+            self.state.lock().unwrap().offline = offline;
+        }
+
+        /// How many conditional requests were answered with "not modified" so far.
+        pub fn not_modified(&self) -> usize {
+            self.state.lock().unwrap().not_modified
+        }
+
+        /// Builds a zip archive holding `files`, e.g. a release to publish with `add_release`.
+        pub fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+            use std::io::Write;
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+            for (name, data) in files {
+                zip.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+                zip.write_all(data).unwrap();
+            }
+            zip.finish().unwrap().into_inner()
+        }
+
+        /// Makes the listing of the archive at `url` claim `size` bytes once extracted, e.g. more than the disk holds.
+        pub fn set_unpacked_size(&self, url: &str, size: u64) {
+            self.state.lock().unwrap().unpacked.insert(url.to_owned(), size);
+        }
+
+        /// Every URL passed to `download` so far, in order.
+        pub fn downloads(&self) -> Vec<String> {
+            self.state.lock().unwrap().downloads.clone()
+        }
+
+        fn asset_url(&self, cdn: &str, kind: &str, channel: &str, name: &str) -> Result<String, anyhow::Error> {
+            let url = FakeBackend::url(cdn, kind, channel, name);
+            match self.state.lock().unwrap().files.contains_key(&url) {
+                true => Ok(url),
+                false => Err(NetError::Status { what: format!("Fake {kind} path"), url, status: reqwest::StatusCode::NOT_FOUND }.into())
+            }
+        }
+    }
+
+    impl ReleaseBackend for FakeBackend {
+        fn config(&self, since: Option<&Validator>) -> Result<Fetched<LauncherConfig>, anyhow::Error> {
+            let mut state = self.state.lock().unwrap();
+            if state.offline {
+                return Err(NetError::Transient("Fake network is down".to_owned()).into());
+            }
+            let config = state.config.clone().ok_or(NetError::Killswitch)?;
+            Ok(state.fetched(config, since))
+        }
+
+        fn channel_data(&self, channel: &str, since: Option<&Validator>) -> Result<Fetched<ConfigResponse>, anyhow::Error> {
+            let mut state = self.state.lock().unwrap();
+            if state.offline {
+                return Err(NetError::Transient("Fake network is down".to_owned()).into());
+            }
+            let data = state.channels.get(channel).cloned()
+                .ok_or_else(|| NetError::BadResponse(format!("Unknown channel '{channel}'")))?;
+            Ok(state.fetched(data, since))
+        }
+
+        fn latest_build(&self, channel: &str) -> Result<String, anyhow::Error> {
+            match self.channel_data(channel, None)? {
+                Fetched::Modified(data, _) => Ok(data.newest_release_name),
+                Fetched::NotModified => unreachable!()
+            }
+        }
+
+        fn upgrade_route(&self, from_channel: &str, to_channel: &str, from_release: &str) -> Result<UpgradePath, anyhow::Error> {
+            let key = (from_channel.to_owned(), to_channel.to_owned(), from_release.to_owned());
+            if let Some(route) = self.state.lock().unwrap().routes.get(&key) {
+                return Ok(route.clone());
+            }
+            match self.latest_build(to_channel)? == from_release && from_channel == to_channel {
+                true => Ok(UpgradePath::NoChangesRequired),
+                false => Err(NetError::BadResponse(format!("No route from {from_channel} {from_release} to {to_channel}")).into())
+            }
+        }
+
+        fn release(&self, channel: &str, name: &str) -> Result<Release, anyhow::Error> {
+            self.state.lock().unwrap().releases.get(&(channel.to_owned(), name.to_owned())).cloned()
+                .ok_or_else(|| NetError::BadResponse(format!("No release '{name}' in channel '{channel}'")).into())
+        }
+
+        fn release_url(&self, cdn: &str, channel: &str, name: &str) -> Result<String, anyhow::Error> {
+            self.asset_url(cdn, "release", channel, name)
+        }
+
+        fn patch_url(&self, cdn: &str, channel: &str, name: &str) -> Result<String, anyhow::Error> {
+            self.asset_url(cdn, "patch", channel, name)
+        }
+
+        fn file_manifest(&self, _cdn: &str, channel: &str, name: &str) -> Result<FileManifest, anyhow::Error> {
+            self.state.lock().unwrap().manifests.get(&(channel.to_owned(), name.to_owned())).cloned()
+                .ok_or_else(|| NetError::BadResponse(format!("No file manifest for '{name}'")).into())
+        }
+
+        fn probe_cdn(&self, cdn: &str, _channel: &str) -> Result<Duration, anyhow::Error> {
+            let state = self.state.lock().unwrap();
+            match state.config.as_ref().is_some_and(|x| x.cdn_regions.iter().any(|x| x == cdn)) {
+                true => Ok(state.latency.get(cdn).copied().unwrap_or_default()),
+                false => Err(NetError::Status { what: "Fake probe".to_owned(), url: cdn.to_owned(), status: reqwest::StatusCode::NOT_FOUND }.into())
+            }
+        }
+
+        fn download(
+            &self,
+            _size: Option<u64>,
+            url: &str,
+            _partial: Option<&Path>,
+            sha256: Option<&str>,
+            _progress: &Progress,
+            cancel: Option<&CancelToken>) -> Result<LoadedFileSource, anyhow::Error> {
+            if let Some(c) = cancel {
+                c.checkpoint().map_err(NetError::from)?;
+            }
+            let mut state = self.state.lock().unwrap();
+            state.downloads.push(url.to_owned());
+            if state.failing.iter().any(|cdn| url.starts_with(&format!("fake://{cdn}/"))) {
+                return Err(NetError::Transient(format!("Fake download from {url} dropped")).into());
+            }
+            let data = match state.files.get(url) {
+                Some(d) => d.clone(),
+                None => return Err(NetError::Status { what: "Fake download".to_owned(), url: url.to_owned(), status: reqwest::StatusCode::NOT_FOUND }.into())
+            };
+            if let Some(expected) = sha256 {
+                let actual = hex::encode(Sha256::digest(&data));
+                if !expected.trim().eq_ignore_ascii_case(&actual) {
+                    return Err(NetError::Integrity { url: url.to_owned(), expected: expected.to_owned(), actual }.into());
+                }
+            }
+            Ok(LoadedFileSource::InMemory(data))
+        }
+
+        fn zip_listing(&self, url: &str) -> Result<Option<ZipListing>, anyhow::Error> {
+            let state = self.state.lock().unwrap();
+            let Some(data) = state.files.get(url) else {
+                return Ok(None);
+            };
+            let listing = ZipListing::read(data.len() as u64, std::io::Cursor::new(data)).ok().flatten();
+            Ok(listing.map(|x| ZipListing { unpacked_size: state.unpacked.get(url).copied().unwrap_or(x.unpacked_size), ..x }))
+        }
+
+        /// A fake patch is a zip of whole files, each replacing the one at the same path.
+        fn apply_patch(&self, dir: &Path, archive: Box<dyn BufReadSeek>, cb: Option<&dyn Fn(f32, String)>) -> Result<(), anyhow::Error> {
+            let mut zip = zip::read::ZipArchive::new(archive)?;
+            let len = zip.len();
+            for i in 0..len {
+                let mut file = zip.by_index(i)?;
+                let name = file.enclosed_name().ok_or_else(|| anyhow::anyhow!("Unsafe path in fake patch"))?;
+                let target = dir.join(&name);
+                if let Some(p) = target.parent() {
+                    std::fs::create_dir_all(p)?;
+                }
+                // Replaced rather than written into, like the real patcher.
+                let _ = std::fs::remove_file(&target);
+                std::io::copy(&mut file, &mut std::fs::File::create(&target)?)?;
+                if let Some(cb) = cb {
+                    cb((i + 1) as f32 / len as f32, name.display().to_string());
+                }
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! What the launcher last heard from the release server, so the window can show it straight away
+//! and the installed game can still be started without the server.
+
+use std::path::PathBuf;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use crate::json::{ConfigResponse, LauncherConfig};
+use crate::net::{Fetched, Validator};
+
+/// A response as it was last fetched.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cached<T> {
+    /// Unix time of the last fetch, including ones the server answered with "not modified".
+    pub fetched: i64,
+    pub validator: Validator,
+    pub data: T
+}
+
+impl<T> Cached<T> {
+    pub fn new(data: T, validator: Validator) -> Cached<T> {
+        Cached { fetched: chrono::Utc::now().timestamp(), validator, data }
+    }
+
+    /// The entry after a conditional fetch of `previous`. `None` if the server says nothing changed
+    /// but there is nothing cached to keep.
+    pub fn refresh(previous: Option<Cached<T>>, fetched: Fetched<T>) -> Option<Cached<T>> {
+        match fetched {
+            Fetched::Modified(data, validator) => Some(Cached::new(data, validator)),
+            Fetched::NotModified => previous.map(|x| Cached { fetched: chrono::Utc::now().timestamp(), ..x })
+        }
+    }
+}
+
+pub struct Cache {
+    dir: PathBuf
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Cache {
+        Cache { dir }
+    }
+
+    /// The launcher's config directory, next to the saved background image.
+    pub fn user() -> Option<Cache> {
+        platform_dirs::AppDirs::new(Some("Procelio Launcher"), true).map(|x| Cache::new(x.config_dir.join("cache")))
+    }
+
+    fn load<T: DeserializeOwned>(&self, name: &str) -> Option<Cached<T>> {
+        let data = std::fs::read(self.dir.join(name)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    fn save<T: Serialize>(&self, name: &str, entry: &Cached<T>) -> Result<(), anyhow::Error> {
+        std::fs::create_dir_all(&self.dir)?;
+        // Written aside and renamed, so a crash mid-write cannot leave a truncated cache behind.
+        let tmp = self.dir.join(format!("{name}.tmp"));
+        std::fs::write(&tmp, serde_json::to_vec(entry)?)?;
+        std::fs::rename(tmp, self.dir.join(name))?;
+        Ok(())
+    }
+
+    pub fn config(&self) -> Option<Cached<LauncherConfig>> {
+        self.load("config.json")
+    }
+
+    pub fn save_config(&self, entry: &Cached<LauncherConfig>) -> Result<(), anyhow::Error> {
+        self.save("config.json", entry)
+    }
+
+    /// The data last fetched for `channel`, if any.
+    pub fn channel(&self, channel: &str) -> Option<Cached<ConfigResponse>> {
+        self.load(&format!("channel-{channel}.json"))
+    }
+
+    pub fn save_channel(&self, channel: &str, entry: &Cached<ConfigResponse>) -> Result<(), anyhow::Error> {
+        self.save(&format!("channel-{channel}.json"), entry)
+    }
+}
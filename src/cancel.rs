@@ -0,0 +1,73 @@
+//! Lets the UI pause or stop a download, unzip or patch running on another thread.
+
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunState {
+    #[default]
+    Running,
+    Paused,
+    Cancelled
+}
+
+/// Returned by [`CancelToken::checkpoint`] once the task has been cancelled.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Shared between the task and whoever controls it. Work checks in with `checkpoint` between chunks.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    state: Arc<(Mutex<RunState>, Condvar)>
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    fn set(&self, state: RunState) {
+        let mut lock = self.state.0.lock().unwrap();
+        if *lock != RunState::Cancelled {
+            *lock = state;
+        }
+        self.state.1.notify_all();
+    }
+
+    pub fn state(&self) -> RunState {
+        *self.state.0.lock().unwrap()
+    }
+
+    pub fn cancel(&self) {
+        self.set(RunState::Cancelled);
+    }
+
+    pub fn pause(&self) {
+        self.set(RunState::Paused);
+    }
+
+    pub fn resume(&self) {
+        self.set(RunState::Running);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state() == RunState::Cancelled
+    }
+
+    /// Blocks while paused. Fails once cancelled, so the task can unwind with `?`.
+    pub fn checkpoint(&self) -> Result<(), Cancelled> {
+        let lock = self.state.0.lock().unwrap();
+        let lock = self.state.1.wait_while(lock, |x| *x == RunState::Paused).unwrap();
+        match *lock {
+            RunState::Cancelled => Err(Cancelled),
+            _ => Ok(())
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! Headless subcommands, so build machines and test rigs can script game updates without a display.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use crate::backend::{HttpBackend, ReleaseBackend};
+use crate::json::UpgradePath;
+use crate::launcher::{Event, LauncherCore};
+use crate::net::{BackendOverride, NetError};
+use crate::patch::PlayGameConfig;
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_NETWORK: i32 = 3;
+pub const EXIT_NOT_INSTALLED: i32 = 4;
+
+pub const COMMANDS: &[&str] = &["install", "update", "launch", "verify", "uninstall", "status"];
+
+const USAGE: &str = "Usage: procelio_launcher_cli <command> [options]
+
+Commands:
+  install    --dir <path> [--channel <name>] [--cdn <region>|auto]  Install or update the game
+  update     --dir <path> [--channel <name>] [--cdn <region>|auto]  Update an existing install
+  launch     --dir <path> [--channel <name>] [--cdn <region>|auto] [-- <game args>]
+                                                                    Update, then run the game and wait for it.
+                                                                    Fails with 1 if the game does not exit cleanly.
+                                                                    Runs the installed version if the server is unreachable
+  verify     --dir <path> [--cdn <region>|auto]                     Check installed files and repair them
+  uninstall  --dir <path>                                           Remove the game
+  status     --dir <path> [--channel <name>] [--json]               Show the installed and latest versions
+
+Global options:
+  --backend <url> [--backend-cert <pem>]   Use a non-production release server
+  --connections <n>                        Split large downloads across n connections (default 1)
+  --verbose                                Include debug output in the log
+
+Exit codes: 0 ok, 1 failure, 2 usage error, 3 network error, 4 not installed";
+
+struct Options {
+    command: String,
+    dir: PathBuf,
+    channel: Option<String>,
+    cdn: Option<String>,
+    json: bool,
+    connections: usize,
+    verbose: bool,
+    backend: Option<BackendOverride>,
+    game_args: Vec<String>
+}
+
+fn parse(args: &[String]) -> Result<Options, String> {
+    let command = args.first().ok_or("Missing command")?.clone();
+    if !COMMANDS.contains(&command.as_str()) {
+        return Err(format!("Unknown command '{command}'"));
+    }
+
+    let mut dir = None;
+    let mut channel = None;
+    let mut cdn = None;
+    let mut json = false;
+    let mut connections = 1;
+    let mut verbose = false;
+    let mut backend = None;
+    let mut backend_cert = None;
+    let mut game_args = Vec::new();
+
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().cloned().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--dir" => dir = Some(PathBuf::from(value()?)),
+            "--channel" => channel = Some(value()?),
+            "--cdn" => cdn = Some(value()?).map(|x| match x.eq_ignore_ascii_case(crate::defs::AUTO_CDN) {
+                true => crate::defs::AUTO_CDN.to_owned(),
+                false => x
+            }),
+            "--backend" => backend = Some(value()?),
+            "--backend-cert" => backend_cert = Some(PathBuf::from(value()?)),
+            "--json" => json = true,
+            "--verbose" => verbose = true,
+            "--connections" => connections = value()?.parse().ok().filter(|x| *x >= 1).ok_or("--connections must be a positive number")?,
+            "--" => {
+                game_args = iter.by_ref().cloned().collect();
+                break;
+            }
+            _ => return Err(format!("Unknown option '{arg}'"))
+        }
+    }
+    if backend.is_none() && backend_cert.is_some() {
+        return Err("--backend-cert needs --backend".to_owned());
+    }
+
+    Ok(Options {
+        command,
+        dir: dir.ok_or("--dir is required")?,
+        channel,
+        cdn,
+        json,
+        connections,
+        verbose,
+        backend: backend.map(|url| BackendOverride { url, cert: backend_cert }),
+        game_args
+    })
+}
+
+/// Runs a subcommand, printing progress to stderr. Returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let opts = match parse(args) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return EXIT_USAGE;
+        }
+    };
+    crate::logging::init(opts.verbose);
+
+    if let Some(o) = opts.backend.clone().or_else(BackendOverride::from_env) {
+        match crate::net::Backend::from_override(&o) {
+            Ok(b) => crate::net::set_backend(b),
+            Err(e) => {
+                eprintln!("{e:?}");
+                return EXIT_USAGE;
+            }
+        }
+    }
+
+    crate::net::set_download_connections(opts.connections);
+    let res = match opts.command.as_str() {
+        "install" => install(&opts).map(|_| EXIT_OK),
+        "update" => update(&opts),
+        "launch" => launch(&opts),
+        "verify" => verify(&opts),
+        "uninstall" => uninstall(&opts),
+        "status" => status(&opts),
+        _ => unreachable!()
+    };
+
+    match res {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            match e.downcast_ref::<NetError>() {
+                Some(e) if !e.is_transient() => EXIT_FAILURE,
+                Some(_) => EXIT_NETWORK,
+                None => EXIT_FAILURE
+            }
+        }
+    }
+}
+
+/// Runs the core until it settles, printing progress to stderr whenever it changes and turning a failure into an error.
+fn wait(core: &mut LauncherCore) -> Result<Vec<Event>, anyhow::Error> {
+    let mut events = Vec::new();
+    let mut last = String::new();
+    while let Some(event) = core.next_event() {
+        match event {
+            Event::Progress(p) => {
+                // The speed changes with every event, so only the percentage and step decide when to print.
+                let line = format!("[{:>3}%] {}", (p.fraction() * 100.) as u32, p);
+                if line != last {
+                    let extra = [p.transfer(), p.overall()].into_iter().flatten().collect::<Vec<_>>();
+                    match extra.is_empty() {
+                        true => eprintln!("{line}"),
+                        false => eprintln!("{line} ({})", extra.join(", "))
+                    }
+                    last = line;
+                }
+            }
+            Event::Failed(e) | Event::Offline(e) => return Err(e),
+            x => events.push(x)
+        }
+    }
+    Ok(events)
+}
+
+/// Loads the config and channel data, defaulting to the installed channel the way the launcher window does.
+fn ready_core(opts: &Options) -> Result<LauncherCore, anyhow::Error> {
+    let installed = crate::patch::get_installed_version(&opts.dir)?;
+    let channel = opts.channel.clone()
+        .or(installed.map(|x| x.channel))
+        .unwrap_or_else(|| "prod".to_owned());
+    let cdn = opts.cdn.clone().unwrap_or_else(|| crate::defs::DEFAULT_CDN.to_owned());
+
+    let mut core = LauncherCore::new(Arc::new(HttpBackend), &channel, &cdn);
+    core.set_cache(crate::cache::Cache::user());
+    core.load_config();
+    wait(&mut core)?;
+    let config = core.config().ok_or_else(|| anyhow::anyhow!("Unable to load launcher config"))?;
+    if core.channel() != channel {
+        return Err(anyhow::anyhow!("Unknown channel '{}', expected one of {:?}", channel, config.channels));
+    }
+    if core.cdn() != cdn {
+        return Err(anyhow::anyhow!("Unknown CDN region '{}', expected one of {:?}", cdn, config.cdn_regions));
+    }
+    Ok(core)
+}
+
+fn not_installed(opts: &Options) -> Result<i32, anyhow::Error> {
+    eprintln!("Procelio is not installed at {}", opts.dir.display());
+    Ok(EXIT_NOT_INSTALLED)
+}
+
+fn install(opts: &Options) -> Result<(PlayGameConfig, crate::json::InstallManifest), anyhow::Error> {
+    let mut core = ready_core(opts)?;
+    let config = core.play_config(&opts.game_args, &[]).ok_or_else(|| anyhow::anyhow!("Unable to load channel data"))?;
+    core.update(opts.dir.clone(), config.clone())?;
+    let events = wait(&mut core)?;
+    match events.into_iter().find_map(|x| match x { Event::Updated(_, m) => Some(m), _ => None }) {
+        Some(m) => {
+            eprintln!("Installed version {} ({})", m.version, m.channel);
+            Ok((config, m))
+        }
+        None => Err(anyhow::anyhow!("Unable to load launch manifest"))
+    }
+}
+
+fn update(opts: &Options) -> Result<i32, anyhow::Error> {
+    if crate::patch::get_installed_version(&opts.dir)?.is_none() {
+        return not_installed(opts);
+    }
+    install(opts).map(|_| EXIT_OK)
+}
+
+fn launch(opts: &Options) -> Result<i32, anyhow::Error> {
+    let (config, manifest) = match (install(opts), crate::patch::get_installed_version(&opts.dir)?) {
+        (Err(e), Some(m)) if crate::launcher::is_offline(&e) => {
+            eprintln!("{e}");
+            eprintln!("Playing offline: version {} ({})", m.version, m.channel);
+            let mut core = LauncherCore::new(Arc::new(HttpBackend), &m.channel, crate::defs::DEFAULT_CDN);
+            core.set_cache(crate::cache::Cache::user());
+            (core.offline_play_config(&m, &opts.game_args, &[]), m)
+        }
+        (res, _) => res?
+    };
+    eprintln!("Launching {}", manifest.exec);
+    let status = crate::patch::game_command(&config, &manifest, &opts.dir)?.status()?;
+    eprintln!("Game exited with {status}");
+    // The game's own codes would be mistaken for ours, e.g. 3 for a network error.
+    Ok(if status.success() { EXIT_OK } else { EXIT_FAILURE })
+}
+
+fn verify(opts: &Options) -> Result<i32, anyhow::Error> {
+    if crate::patch::get_installed_version(&opts.dir)?.is_none() {
+        return not_installed(opts);
+    }
+    let cdn = opts.cdn.clone().unwrap_or_else(|| crate::defs::DEFAULT_CDN.to_owned());
+    let mut core = LauncherCore::new(Arc::new(HttpBackend), "prod", &cdn);
+    if cdn == crate::defs::AUTO_CDN {
+        // Regions can only be ranked once the config says which there are.
+        core.load_config();
+        wait(&mut core)?;
+    }
+    core.verify(opts.dir.clone())?;
+    let events = wait(&mut core)?;
+    for event in events {
+        if let Event::Verified(report) = event {
+            eprintln!("Checked {} files, repaired {}", report.checked, report.repaired.len());
+            for file in report.repaired {
+                eprintln!("  {file}");
+            }
+        }
+    }
+    Ok(EXIT_OK)
+}
+
+fn uninstall(opts: &Options) -> Result<i32, anyhow::Error> {
+    if crate::patch::get_installed_version(&opts.dir)?.is_none() {
+        return not_installed(opts);
+    }
+    let mut core = LauncherCore::new(Arc::new(HttpBackend), "prod", crate::defs::DEFAULT_CDN);
+    core.uninstall(opts.dir.clone())?;
+    wait(&mut core)?;
+    eprintln!("Uninstalled {}", opts.dir.display());
+    Ok(EXIT_OK)
+}
+
+fn status(opts: &Options) -> Result<i32, anyhow::Error> {
+    let installed = crate::patch::get_installed_version(&opts.dir)?;
+    let channel = opts.channel.clone()
+        .or(installed.as_ref().map(|x| x.channel.clone()))
+        .unwrap_or_else(|| "prod".to_owned());
+    let backend = HttpBackend;
+    let latest = backend.latest_build(&channel)?;
+    let up_to_date = match &installed {
+        Some(m) => Some(matches!(backend.upgrade_route(&m.channel, &channel, &m.version)?, UpgradePath::NoChangesRequired)),
+        None => None
+    };
+
+    if opts.json {
+        let status = serde_json::json!({
+            "dir": opts.dir,
+            "installed": installed.as_ref().map(|x| serde_json::json!({ "version": x.version, "channel": x.channel, "exec": x.exec })),
+            "channel": channel,
+            "latest": latest,
+            "up_to_date": up_to_date
+        });
+        println!("{status}");
+    } else {
+        match &installed {
+            Some(m) => println!("Installed: {} ({}) at {}", m.version, m.channel, opts.dir.display()),
+            None => println!("Installed: no ({})", opts.dir.display())
+        }
+        println!("Latest {}: {}", channel, latest);
+        if let Some(u) = up_to_date {
+            println!("Up to date: {}", if u { "yes" } else { "no" });
+        }
+    }
+    Ok(if installed.is_some() { EXIT_OK } else { EXIT_NOT_INSTALLED })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_launch_args() {
+        let opts = parse(&args(&["launch", "--dir", "/games/Procelio", "--channel", "dev", "--", "-screen-fullscreen", "0"])).unwrap();
+        assert_eq!(opts.command, "launch");
+        assert_eq!(opts.dir, PathBuf::from("/games/Procelio"));
+        assert_eq!(opts.channel.as_deref(), Some("dev"));
+        assert_eq!(opts.game_args, vec!["-screen-fullscreen", "0"]);
+        assert!(!opts.verbose);
+        assert!(opts.backend.is_none());
+        // Everything after `--` belongs to the game, even options the launcher knows.
+        let opts = parse(&args(&["launch", "--dir", "x", "--backend", "http://127.0.0.1:9630", "--", "--backend", "http://other"])).unwrap();
+        assert_eq!(opts.backend.map(|x| x.url).as_deref(), Some("http://127.0.0.1:9630"));
+        assert_eq!(opts.game_args, vec!["--backend", "http://other"]);
+        assert!(parse(&args(&["status", "--dir", "x", "--verbose"])).unwrap().verbose);
+
+        assert!(parse(&args(&["status"])).is_err());
+        assert!(parse(&args(&["explode", "--dir", "x"])).is_err());
+        assert!(parse(&args(&["status", "--dir"])).is_err());
+    }
+}
@@ -0,0 +1,11 @@
+#![forbid(unsafe_code)]
+#![cfg_attr(not(debug_assertions), deny(warnings))] // Forbid warnings in release builds
+#![warn(clippy::all, rust_2018_idioms)]
+
+//! The headless commands as a console program. In Windows release builds the launcher itself has no
+//! console, so its output goes nowhere and `cmd` does not wait for its exit code; scripts use this instead.
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    std::process::exit(procelio_launcher::cli::run(&args[1..]));
+}
@@ -2,6 +2,10 @@ pub const CURRENT_README: i32 = 4534;
 pub const LICENSE: &str = include_str!("resources/licenses.txt");
 pub const URL: &str = "https://releases.procelio.com:9630";
 pub const KILLSWITCHURL: &str = "https://releases.procelio.com:9987";
+/// The download region used when the saved one is no longer offered, or before regions are probed.
+pub const DEFAULT_CDN: &str = "nyc3";
+/// Selects whichever download region answers fastest.
+pub const AUTO_CDN: &str = "Auto";
 
 pub const KILLSWITCHCERT: &[u8] =
 "-----BEGIN CERTIFICATE-----
@@ -1,43 +1,163 @@
-use std::io::Write;
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::progress::format_bytes;
 
+/// The default for [`memory_limit`]. Anything bigger goes to a temp file.
+pub const DEFAULT_MEMORY_LIMIT: u64 = 64 * 1024 * 1024;
+
+static MEMORY_LIMIT: AtomicU64 = AtomicU64::new(DEFAULT_MEMORY_LIMIT);
+
+/// The most bytes a single download may hold in memory before it spills to disk.
+pub fn memory_limit() -> u64 {
+    MEMORY_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Replaces the in-memory ceiling for every following download. `0` always uses a temp file.
+pub fn set_memory_limit(bytes: u64) {
+    MEMORY_LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+/// Where a download is written while it arrives. Starts in memory when the expected size fits
+/// under [`memory_limit`], and moves to a temp file as soon as it grows past it.
 pub enum LoadingFileSource {
     InMemory(Vec<u8>),
     OnDisk(std::io::BufWriter<std::fs::File>)
 }
 
 impl LoadingFileSource {
-    pub fn new(file_len: Option<u64>) -> Result<LoadingFileSource, anyhow::Error> {
+    pub fn new(file_len: Option<u64>) -> Result<LoadingFileSource, std::io::Error> {
         Ok(match file_len {
-            Some(x) => {
-                if x < 1024*1024*1024 {
-                    let contents = Vec::with_capacity(x.try_into().unwrap());
-                    println!("In-Memory File");
-                    LoadingFileSource::InMemory(contents)
-                } else {
-                    println!("Disk File");
-                    LoadingFileSource::OnDisk(std::io::BufWriter::new(tempfile::tempfile()?))
-                }
-            }
-            _ => {
-                println!("Disk File");
-                LoadingFileSource::OnDisk(std::io::BufWriter::new(tempfile::tempfile()?))
-            }
+            Some(x) if x <= memory_limit() => LoadingFileSource::InMemory(Vec::with_capacity(x as usize)),
+            _ => LoadingFileSource::OnDisk(std::io::BufWriter::new(tempfile::tempfile()?))
         })
     }
 
     pub fn add(&mut self, slice: &[u8]) -> Result<(), anyhow::Error> {
-        match self {
-            LoadingFileSource::InMemory(v) => {
-                v.extend_from_slice(slice);
-                Ok(())
-            },
+        Ok(self.write_all(slice)?)
+    }
+
+    /// Flushes anything still buffered and rewinds a temp file so it can be read back.
+    pub fn finish(self) -> Result<LoadedFileSource, std::io::Error> {
+        Ok(match self {
+            LoadingFileSource::InMemory(v) => LoadedFileSource::InMemory(v),
             LoadingFileSource::OnDisk(f) => {
-                f.write_all(slice).map_err(|x|x.into())
+                let mut file = f.into_inner().map_err(|e| e.into_error())?;
+                file.rewind()?;
+                LoadedFileSource::OnDisk(file)
             }
+        })
+    }
+}
+
+impl Write for LoadingFileSource {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let LoadingFileSource::InMemory(v) = self && (v.len() + buf.len()) as u64 > memory_limit() {
+            // The server sent more than it said it would, or the limit was lowered mid-download.
+            let mut file = std::io::BufWriter::new(tempfile::tempfile()?);
+            file.write_all(v)?;
+            *self = LoadingFileSource::OnDisk(file);
+        }
+        match self {
+            LoadingFileSource::InMemory(v) => v.write(buf),
+            LoadingFileSource::OnDisk(f) => f.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            LoadingFileSource::InMemory(_) => Ok(()),
+            LoadingFileSource::OnDisk(f) => f.flush()
         }
     }
 }
 
+/// Free space on the volume holding `path`. `path` does not have to exist yet, e.g. a new install directory.
+pub fn available_space(path: &Path) -> Result<u64, std::io::Error> {
+    let existing = path.ancestors().find(|x| x.exists()).unwrap_or(path);
+    fs4::available_space(existing)
+}
+
+/// Fails with a "need X, have Y" message if the volume holding `path` has less than `need` bytes free.
+pub fn ensure_space(path: &Path, need: u64) -> Result<(), anyhow::Error> {
+    let have = available_space(path)?;
+    if have < need {
+        return Err(anyhow::anyhow!("Not enough disk space on {}: need {}, have {}",
+            path.display(), format_bytes(need), format_bytes(have)));
+    }
+    Ok(())
+}
+
+/// Replaces the file at `path` with a copy of itself, so it no longer shares its data with any hard links to it.
+pub fn unshare(path: &Path) -> Result<(), std::io::Error> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".unshare");
+    let tmp = path.with_file_name(name);
+    std::fs::copy(path, &tmp)?;
+    std::fs::rename(tmp, path)
+}
+
+/// A log file that is moved aside to `name.1.ext` once it grows past `max` bytes, keeping up to `keep` old files.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: std::fs::File,
+    written: u64,
+    max: u64,
+    keep: usize
+}
+
+impl RotatingFile {
+    /// Starts a new file at `path`, moving any previous one aside.
+    pub fn create(path: PathBuf, max: u64, keep: usize) -> Result<RotatingFile, std::io::Error> {
+        if let Some(p) = path.parent() {
+            std::fs::create_dir_all(p)?;
+        }
+        rotate(&path, keep)?;
+        let file = std::fs::File::create(&path)?;
+        Ok(RotatingFile { path, file, written: 0, max, keep })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write_line(&mut self, line: &str) -> Result<(), std::io::Error> {
+        if self.written > 0 && self.written + line.len() as u64 + 1 > self.max {
+            rotate(&self.path, self.keep)?;
+            self.file = std::fs::File::create(&self.path)?;
+            self.written = 0;
+        }
+        writeln!(self.file, "{line}")?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// The `n`th older copy of a rotated file, e.g. `game.2.log` for `game.log`.
+pub fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".{n}"));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+fn rotate(path: &Path, keep: usize) -> Result<(), std::io::Error> {
+    let ignore_missing = |res: std::io::Result<()>| match res {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => res
+    };
+    if keep == 0 {
+        return ignore_missing(std::fs::remove_file(path));
+    }
+    for n in (1..keep).rev() {
+        ignore_missing(std::fs::rename(rotated_path(path, n), rotated_path(path, n + 1)))?;
+    }
+    ignore_missing(std::fs::rename(path, rotated_path(path, 1)))
+}
+
 pub enum LoadedFileSource {
     InMemory(Vec<u8>),
     OnDisk(std::fs::File)
@@ -47,13 +167,6 @@ pub trait BufReadSeek: std::io::BufRead + std::io::Seek {}
 impl<T: std::io::BufRead + std::io::Seek> BufReadSeek for T {}
 
 impl LoadedFileSource {
-    pub fn new(loading: LoadingFileSource) -> LoadedFileSource {
-        match loading {
-            LoadingFileSource::InMemory(v) => LoadedFileSource::InMemory(v),
-            LoadingFileSource::OnDisk(f) => LoadedFileSource::OnDisk(f.into_inner().unwrap())
-        }
-    } 
-    
     pub fn as_reader(self) -> Box<dyn BufReadSeek> {
         match self {
             LoadedFileSource::InMemory(v) => {
@@ -65,3 +178,57 @@ impl LoadedFileSource {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_spills_past_memory_limit() {
+        let limit = memory_limit();
+        assert!(matches!(LoadingFileSource::new(None).unwrap(), LoadingFileSource::OnDisk(_)));
+        assert!(matches!(LoadingFileSource::new(Some(limit + 1)).unwrap(), LoadingFileSource::OnDisk(_)));
+
+        // A size that fits starts in memory, but moves to disk once more than the limit arrives.
+        let mut src = LoadingFileSource::new(Some(limit)).unwrap();
+        src.add(&vec![1; limit as usize]).unwrap();
+        assert!(matches!(src, LoadingFileSource::InMemory(_)));
+        src.add(&[2; 10]).unwrap();
+        assert!(matches!(src, LoadingFileSource::OnDisk(_)));
+
+        let mut data = Vec::new();
+        src.finish().unwrap().as_reader().read_to_end(&mut data).unwrap();
+        assert_eq!(data.len() as u64, limit + 10);
+        assert_eq!(&data[data.len() - 11..], &[1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_ensure_space() {
+        let dir = tempfile::tempdir().unwrap();
+        // A directory that will only be created by the install still has a volume to check.
+        let target = dir.path().join("Procelio").join("staging");
+        ensure_space(&target, 0).unwrap();
+        let err = ensure_space(&target, u64::MAX).unwrap_err().to_string();
+        assert!(err.starts_with("Not enough disk space") && err.contains(": need 16777216 TB, have "), "{err}");
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("game.log");
+        let mut log = RotatingFile::create(path.clone(), 10, 2).unwrap();
+        log.write_line("first").unwrap();
+        log.write_line("second").unwrap();
+        log.write_line("third").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "third\n");
+        assert_eq!(std::fs::read_to_string(rotated_path(&path, 1)).unwrap(), "second\n");
+        assert_eq!(std::fs::read_to_string(rotated_path(&path, 2)).unwrap(), "first\n");
+
+        // A new file moves the old ones along, dropping the oldest.
+        RotatingFile::create(path.clone(), 10, 2).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(std::fs::read_to_string(rotated_path(&path, 2)).unwrap(), "second\n");
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
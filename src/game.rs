@@ -0,0 +1,251 @@
+//! Runs the installed game and keeps an eye on it, so a crash can be reported with its output.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use serde::{Serialize, Deserialize};
+use crate::files::RotatingFile;
+use crate::patch::PlayGameConfig;
+
+/// Past this the log moves to `game.1.log` and starts again.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+/// Previous logs kept, one per launch unless a run is long enough to rotate.
+const KEEP_LOGS: usize = 5;
+/// Lines of output kept in memory for the crash report.
+const TAIL_LINES: usize = 100;
+
+/// Where game output is written, in the launcher's data directory.
+pub fn log_path() -> Option<PathBuf> {
+    platform_dirs::AppDirs::new(Some("Procelio Launcher"), true).map(|x| x.data_dir.join("logs").join("game.log"))
+}
+
+/// Extra arguments and environment variables for the game, as typed in the settings.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LaunchOptions {
+    /// Shell-style, e.g. `-screen-fullscreen 0 -logFile "C:/My Logs/game.log"`.
+    pub args: String,
+    /// Shell-style `KEY=VALUE` pairs.
+    pub env: String
+}
+
+impl LaunchOptions {
+    pub fn args(&self) -> Result<Vec<String>, anyhow::Error> {
+        shell_words::split(&self.args).map_err(|e| anyhow::anyhow!("Invalid launch arguments: {e}"))
+    }
+
+    pub fn env(&self) -> Result<Vec<(String, String)>, anyhow::Error> {
+        let words = shell_words::split(&self.env).map_err(|e| anyhow::anyhow!("Invalid environment variables: {e}"))?;
+        words.into_iter().map(|x| match x.split_once('=') {
+            Some((k, v)) if !k.is_empty() && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => Ok((k.to_owned(), v.to_owned())),
+            _ => Err(anyhow::anyhow!("Invalid environment variable {x:?}, expected KEY=VALUE"))
+        }).collect()
+    }
+}
+
+/// Launch options for every channel, and the channels that replace them with their own.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LaunchSettings {
+    pub all: LaunchOptions,
+    pub channels: BTreeMap<String, LaunchOptions>,
+    /// A command the game is run through on Linux, e.g. `gamemoderun %command%`. Checked by [`wrap`] before it is saved.
+    pub wrapper: String
+}
+
+impl LaunchSettings {
+    pub fn for_channel(&self, channel: &str) -> &LaunchOptions {
+        self.channels.get(channel).unwrap_or(&self.all)
+    }
+}
+
+/// Stands for the game and its arguments in a wrapper template.
+pub const COMMAND_PLACEHOLDER: &str = "%command%";
+
+/// The full command line for `program` and `args` run through `wrapper`, with `%command%` replaced by them.
+/// An empty `wrapper` leaves the command as is.
+pub fn wrap(wrapper: &str, program: &str, args: &[String]) -> Result<Vec<String>, anyhow::Error> {
+    let command = std::iter::once(program.to_owned()).chain(args.iter().cloned());
+    if wrapper.trim().is_empty() {
+        return Ok(command.collect());
+    }
+    let words = shell_words::split(wrapper).map_err(|e| anyhow::anyhow!("Invalid wrapper command: {e}"))?;
+    match words.iter().filter(|x| *x == COMMAND_PLACEHOLDER).count() {
+        1 => { },
+        0 => return Err(anyhow::anyhow!("Wrapper command must contain {COMMAND_PLACEHOLDER}, e.g. gamemoderun {COMMAND_PLACEHOLDER}")),
+        _ => return Err(anyhow::anyhow!("Wrapper command must contain {COMMAND_PLACEHOLDER} only once"))
+    }
+    let mut command = Some(command);
+    Ok(words.into_iter().flat_map(|x| match x == COMMAND_PLACEHOLDER {
+        true => command.take().into_iter().flatten().collect(),
+        false => vec![x]
+    }).collect())
+}
+
+/// The command `config` runs `program` with, written the way a shell would take it.
+pub fn command_line(program: &str, config: &PlayGameConfig) -> Result<String, anyhow::Error> {
+    let env = config.env.iter().map(|(k, v)| format!("{k}={}", shell_words::quote(v)));
+    let argv = wrap(&config.wrapper, program, &config.args)?;
+    Ok(env.chain(argv.iter().map(|x| shell_words::quote(x).into_owned())).collect::<Vec<_>>().join(" "))
+}
+
+/// How the game ended.
+#[derive(Clone, Debug)]
+pub struct GameExit {
+    /// `None` if it was killed by a signal.
+    pub code: Option<i32>,
+    pub success: bool,
+    /// E.g. "exit status: 1" or "signal: 11 (SIGSEGV)".
+    pub status: String,
+    pub log: Option<PathBuf>,
+    /// The last lines of output, oldest first.
+    pub tail: Vec<String>
+}
+
+impl GameExit {
+    /// A non-zero exit or a signal.
+    pub fn crashed(&self) -> bool {
+        !self.success
+    }
+}
+
+struct Output {
+    log: Option<RotatingFile>,
+    tail: VecDeque<String>
+}
+
+/// A game that has been started. Dropping this leaves it running, unwatched.
+pub struct RunningGame {
+    child: Child,
+    output: Arc<Mutex<Output>>,
+    readers: Vec<JoinHandle<()>>
+}
+
+/// Starts `command` with its stdout and stderr copied to a new log at `log` and kept for the crash report.
+pub fn spawn(mut command: Command, log: Option<&Path>) -> Result<RunningGame, anyhow::Error> {
+    let file = match log.map(|x| RotatingFile::create(x.to_owned(), MAX_LOG_SIZE, KEEP_LOGS)).transpose() {
+        Ok(f) => f,
+        Err(e) => {
+            // The game matters more than its log.
+            log::warn!("Unable to create game log {:?}: {e}", log);
+            None
+        }
+    };
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+        .map_err(|e| anyhow::anyhow!("Unable to start {:?}: {e}", command.get_program()))?;
+    let output = Arc::new(Mutex::new(Output { log: file, tail: VecDeque::new() }));
+    let mut readers = Vec::new();
+    if let Some(out) = child.stdout.take() {
+        readers.push(copy_lines(out, output.clone()));
+    }
+    if let Some(err) = child.stderr.take() {
+        readers.push(copy_lines(err, output.clone()));
+    }
+    Ok(RunningGame { child, output, readers })
+}
+
+fn copy_lines<R: Read + Send + 'static>(stream: R, output: Arc<Mutex<Output>>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            // Games do not always write UTF-8, and one bad line should not end the log.
+            let line = match line {
+                Ok(l) => l,
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => "<unreadable output>".to_owned(),
+                Err(_) => break
+            };
+            let mut output = output.lock().unwrap();
+            if let Some(log) = &mut output.log
+                && let Err(e) = log.write_line(&line) {
+                log::warn!("Unable to write game log: {e}");
+                output.log = None;
+            }
+            if output.tail.len() == TAIL_LINES {
+                output.tail.pop_front();
+            }
+            output.tail.push_back(line);
+        }
+    })
+}
+
+impl RunningGame {
+    /// Blocks until the game exits and all of its output has been logged.
+    pub fn wait(mut self) -> Result<GameExit, anyhow::Error> {
+        let status = self.child.wait()?;
+        for reader in self.readers.drain(..) {
+            let _ = reader.join();
+        }
+        let output = self.output.lock().unwrap();
+        log::info!("Game exited with {status}");
+        Ok(GameExit {
+            code: status.code(),
+            success: status.success(),
+            status: status.to_string(),
+            log: output.log.as_ref().map(|x| x.path().to_owned()),
+            tail: output.tail.iter().cloned().collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_launch_options() {
+        let mut settings = LaunchSettings::default();
+        settings.all.args = r#"-screen-fullscreen 0 -logFile "my log.txt""#.to_owned();
+        settings.all.env = "UNITY_DEBUG=1 GREETING='hello world'".to_owned();
+        settings.channels.insert("dev".to_owned(), LaunchOptions { args: "-dev".to_owned(), env: String::new() });
+
+        let all = settings.for_channel("prod");
+        assert_eq!(all.args().unwrap(), vec!["-screen-fullscreen", "0", "-logFile", "my log.txt"]);
+        let env = all.env().unwrap();
+        assert_eq!(env, vec![("UNITY_DEBUG".to_owned(), "1".to_owned()), ("GREETING".to_owned(), "hello world".to_owned())]);
+        assert_eq!(settings.for_channel("dev").args().unwrap(), vec!["-dev"]);
+
+        let mut config = PlayGameConfig { cdns: Vec::new(), channel: "prod".to_owned(), latest_build: "1.0".to_owned(), args: all.args().unwrap(), env, wrapper: String::new() };
+        assert_eq!(command_line("./Procelio", &config).unwrap(), "UNITY_DEBUG=1 GREETING='hello world' ./Procelio -screen-fullscreen 0 -logFile 'my log.txt'");
+        config.wrapper = "env FOO=1 mangohud %command%".to_owned();
+        assert_eq!(command_line("./Procelio", &config).unwrap(),
+            "UNITY_DEBUG=1 GREETING='hello world' env 'FOO=1' mangohud ./Procelio -screen-fullscreen 0 -logFile 'my log.txt'");
+
+        assert!(LaunchOptions { args: "-logFile \"unclosed".to_owned(), env: String::new() }.args().is_err());
+        assert!(LaunchOptions { args: String::new(), env: "=1 NOVALUE".to_owned() }.env().is_err());
+    }
+
+    #[test]
+    fn test_wrap() {
+        let args = vec!["-x".to_owned()];
+        assert_eq!(wrap("", "game", &args).unwrap(), vec!["game", "-x"]);
+        assert_eq!(wrap("gamemoderun %command% -extra", "game", &args).unwrap(), vec!["gamemoderun", "game", "-x", "-extra"]);
+        assert!(wrap("gamemoderun", "game", &args).is_err());
+        assert!(wrap("%command% %command%", "game", &args).is_err());
+        assert!(wrap("prime-run 'unclosed %command%", "game", &args).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_crash_is_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("game.log");
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo starting; echo 'out of memory' >&2; exit 3"]);
+        let exit = spawn(command, Some(&log)).unwrap().wait().unwrap();
+
+        assert!(exit.crashed());
+        assert_eq!(exit.code, Some(3));
+        assert_eq!(exit.log.as_deref(), Some(log.as_path()));
+        let mut tail = exit.tail.clone();
+        tail.sort();
+        assert_eq!(tail, vec!["out of memory", "starting"]);
+        let logged = std::fs::read_to_string(&log).unwrap();
+        assert!(logged.contains("starting\n") && logged.contains("out of memory\n"), "{logged}");
+
+        let exit = spawn(Command::new("true"), Some(&log)).unwrap().wait().unwrap();
+        assert!(!exit.crashed());
+        assert!(exit.tail.is_empty());
+        assert!(crate::files::rotated_path(&log, 1).exists());
+    }
+}
@@ -18,7 +18,10 @@ pub struct Release {
     pub download_size: u64,
     pub title: String,
     pub description: String,
-    pub changelog: String
+    pub changelog: String,
+    /// Hex SHA-256 of the release zip, if the server publishes one.
+    #[serde(default)]
+    pub sha256: Option<String>
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -30,6 +33,29 @@ pub struct Patch {
     pub to_channel: String,
     pub from_name: String,
     pub to_name: String,
+    /// Hex SHA-256 of the patch zip, if the server publishes one.
+    #[serde(default)]
+    pub sha256: Option<String>
+}
+
+/// One file of an installed release, as listed in the server's per-file manifest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    /// Relative to the install directory, `/`-separated.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileManifest {
+    pub files: Vec<FileEntry>
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub repaired: Vec<String>
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -111,7 +137,7 @@ pub struct PatchList {
     pub patches: Vec<String>
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct InstallManifest {
     pub exec: String,
     pub version: String,
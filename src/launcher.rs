@@ -0,0 +1,901 @@
+//! The launcher's install/launch flow without any UI. The window and the headless commands both drive a `LauncherCore`.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use crate::backend::ReleaseBackend;
+use crate::cache::{Cache, Cached};
+use crate::cancel::{CancelToken, RunState};
+use crate::game::GameExit;
+use crate::defs::{AUTO_CDN, DEFAULT_CDN};
+use crate::json::{ConfigResponse, InstallManifest, LauncherConfig, VerifyReport};
+use crate::net::Fetched;
+use crate::patch::PlayGameConfig;
+use crate::progress::{Phase, Progress, ProgressEvent};
+
+/// How long [`LauncherCore::shutdown`] waits for a cancelled task to stop.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Long-running work on an install. Only one runs at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Task {
+    Update,
+    Launch,
+    Verify,
+    Uninstall
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoreState {
+    /// No channel data yet, and nothing being fetched.
+    Idle,
+    LoadingConfig,
+    LoadingChannel,
+    /// Timing each download region to pick one for "Auto".
+    ProbingCdn,
+    /// Config and channel data are loaded, so the game can be installed or launched.
+    Ready,
+    /// The game is running, so it cannot be updated or started again.
+    Playing,
+    /// The release server could not be reached. An installed game can still be started with `launch_offline`.
+    Offline,
+    Busy(Task)
+}
+
+/// What finished since the last `poll`.
+#[derive(Debug)]
+pub enum Event {
+    ConfigLoaded,
+    ChannelLoaded,
+    /// The download regions have been timed. See `regions` for the result.
+    CdnProbed,
+    /// Loading the config or channel data failed because the release server could not be reached.
+    Offline(anyhow::Error),
+    /// An update finished. The game is also running if the task was `Task::Launch`.
+    Updated(Task, InstallManifest),
+    /// The game started by `launch` or `launch_offline` has exited.
+    GameExited(GameExit),
+    Verified(VerifyReport),
+    Uninstalled,
+    /// The task was cancelled and has finished cleaning up. The previous install is unchanged.
+    Cancelled(Task),
+    Failed(anyhow::Error),
+    /// The running task moved on. The latest one is also kept in `progress`.
+    Progress(ProgressEvent)
+}
+
+enum Msg {
+    Config(Result<Fetched<LauncherConfig>, anyhow::Error>),
+    Channel(String, Result<Fetched<ConfigResponse>, anyhow::Error>),
+    Probed(Vec<(String, Result<Duration, anyhow::Error>)>),
+    Progress(ProgressEvent),
+    Exited(Result<GameExit, anyhow::Error>),
+    Done(Result<Event, anyhow::Error>)
+}
+
+pub struct LauncherCore {
+    backend: Arc<dyn ReleaseBackend>,
+    channel: String,
+    cdn: String,
+    /// Taken from the cache until the server answers.
+    config: Option<Cached<LauncherConfig>>,
+    channel_data: Option<Cached<ConfigResponse>>,
+    loading_config: bool,
+    loading_channel: bool,
+    probing: bool,
+    /// Download regions from fastest to slowest, once probed.
+    ranked: Vec<String>,
+    offline: bool,
+    cache: Option<Cache>,
+    /// Games started and not yet exited. Shared with the threads waiting on them.
+    playing: Arc<AtomicUsize>,
+    task: Option<Task>,
+    progress: Option<ProgressEvent>,
+    cancel: Option<CancelToken>,
+    send: Sender<Msg>,
+    recv: Receiver<Msg>
+}
+
+impl LauncherCore {
+    pub fn new(backend: Arc<dyn ReleaseBackend>, channel: &str, cdn: &str) -> LauncherCore {
+        let (send, recv) = std::sync::mpsc::channel();
+        LauncherCore {
+            backend,
+            channel: channel.to_owned(),
+            cdn: cdn.to_owned(),
+            config: None,
+            channel_data: None,
+            loading_config: false,
+            loading_channel: false,
+            probing: false,
+            ranked: Vec::new(),
+            offline: false,
+            cache: None,
+            playing: Arc::new(AtomicUsize::new(0)),
+            task: None,
+            progress: None,
+            cancel: None,
+            send,
+            recv
+        }
+    }
+
+    /// Where channel data is kept for offline launches. Nothing is cached without one.
+    pub fn set_cache(&mut self, cache: Option<Cache>) {
+        self.cache = cache;
+    }
+
+    pub fn backend(&self) -> &Arc<dyn ReleaseBackend> {
+        &self.backend
+    }
+
+    pub fn state(&self) -> CoreState {
+        match self.task {
+            Some(t) => CoreState::Busy(t),
+            None if self.playing.load(Ordering::Relaxed) > 0 => CoreState::Playing,
+            None if self.loading_config => CoreState::LoadingConfig,
+            None if self.loading_channel => CoreState::LoadingChannel,
+            None if self.probing => CoreState::ProbingCdn,
+            None if self.offline => CoreState::Offline,
+            None if self.channel_data.is_some() => CoreState::Ready,
+            None => CoreState::Idle
+        }
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// The selected download region, which may be `AUTO_CDN`.
+    pub fn cdn(&self) -> &str {
+        &self.cdn
+    }
+
+    /// Download regions to use, best first: the selected one, then the others by latency once probed,
+    /// or in the server's order before that.
+    pub fn regions(&self) -> Vec<String> {
+        let offered = self.config().map(|x| x.cdn_regions.clone()).unwrap_or_default();
+        let selected = Some(self.cdn.clone()).filter(|x| x != AUTO_CDN);
+        let order = selected.into_iter()
+            .chain(self.ranked.iter().cloned())
+            .chain(std::iter::once(DEFAULT_CDN.to_owned()))
+            .chain(offered.iter().cloned());
+        let mut regions: Vec<String> = Vec::new();
+        for cdn in order {
+            if !regions.contains(&cdn) && (offered.is_empty() || offered.contains(&cdn)) {
+                regions.push(cdn);
+            }
+        }
+        regions
+    }
+
+    /// The region downloads start from.
+    pub fn best_cdn(&self) -> String {
+        self.regions().into_iter().next().unwrap_or_else(|| DEFAULT_CDN.to_owned())
+    }
+
+    pub fn config(&self) -> Option<&LauncherConfig> {
+        self.config.as_ref().map(|x| &x.data)
+    }
+
+    pub fn channel_data(&self) -> Option<&ConfigResponse> {
+        self.channel_data.as_ref().map(|x| &x.data)
+    }
+
+    /// When the channel data on show was fetched, as a local time.
+    pub fn fetched(&self) -> Option<chrono::DateTime<chrono::Local>> {
+        let fetched = self.channel_data.as_ref()?.fetched;
+        chrono::DateTime::from_timestamp(fetched, 0).map(|x| x.with_timezone(&chrono::Local))
+    }
+
+    /// The latest progress of the running task, if any.
+    pub fn progress(&self) -> Option<&ProgressEvent> {
+        self.progress.as_ref()
+    }
+
+    /// Whether the running task is running, paused or being cancelled.
+    pub fn run_state(&self) -> Option<RunState> {
+        self.cancel.as_ref().map(|x| x.state())
+    }
+
+    pub fn pause(&self) {
+        if let Some(c) = &self.cancel {
+            c.pause();
+        }
+    }
+
+    pub fn resume(&self) {
+        if let Some(c) = &self.cancel {
+            c.resume();
+        }
+    }
+
+    /// Asks the running task to stop. It reports `Event::Cancelled` once it has cleaned up.
+    pub fn cancel(&self) {
+        if let Some(c) = &self.cancel {
+            c.cancel();
+        }
+    }
+
+    /// Cancels the running task and waits up to [`SHUTDOWN_TIMEOUT`] for it to clean up, e.g. before the window closes.
+    pub fn shutdown(&mut self) {
+        self.cancel();
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while self.task.is_some() {
+            match self.recv.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(msg) => { self.handle(msg); },
+                Err(RecvTimeoutError::Timeout) => {
+                    // Most likely a patch, which cannot stop halfway. It works on a staged copy that the next start throws away.
+                    log::warn!("{:?} did not stop within {:?}, leaving it to exit with the launcher", self.task, SHUTDOWN_TIMEOUT);
+                    break;
+                }
+                Err(RecvTimeoutError::Disconnected) => break
+            }
+        }
+    }
+
+    /// Fetches the launcher config, then the data for the selected channel. Whatever was cached from
+    /// last time is shown meanwhile, and sent along so the server only answers with what changed.
+    pub fn load_config(&mut self) {
+        self.config = self.cache.as_ref().and_then(|x| x.config());
+        self.channel_data = self.cache.as_ref().and_then(|x| x.channel(&self.channel));
+        self.offline = false;
+        self.loading_config = true;
+        let (backend, send) = (self.backend.clone(), self.send.clone());
+        let since = self.config.as_ref().map(|x| x.validator.clone());
+        std::thread::spawn(move || {
+            let _ = send.send(Msg::Config(backend.config(since.as_ref())));
+        });
+    }
+
+    /// Switches channel and fetches its data. Responses for a previously selected channel are dropped.
+    pub fn select_channel(&mut self, channel: &str) {
+        self.channel = channel.to_owned();
+        self.channel_data = self.cache.as_ref().and_then(|x| x.channel(channel));
+        if self.config.is_none() || self.loading_config {
+            return;
+        }
+        self.loading_channel = true;
+        let (backend, send, channel) = (self.backend.clone(), self.send.clone(), channel.to_owned());
+        let since = self.channel_data.as_ref().map(|x| x.validator.clone());
+        std::thread::spawn(move || {
+            let res = backend.channel_data(&channel, since.as_ref());
+            let _ = send.send(Msg::Channel(channel, res));
+        });
+    }
+
+    /// Selects a download region. `AUTO_CDN` probes the regions if they have not been yet.
+    pub fn select_cdn(&mut self, cdn: &str) {
+        self.cdn = cdn.to_owned();
+        if cdn == AUTO_CDN && self.ranked.is_empty() {
+            self.probe_cdns();
+        }
+    }
+
+    /// Times every region the config offers, in parallel.
+    fn probe_cdns(&mut self) {
+        let regions = match self.config() {
+            Some(c) if !self.probing => c.cdn_regions.clone(),
+            _ => return
+        };
+        self.probing = true;
+        let (backend, send, channel) = (self.backend.clone(), self.send.clone(), self.channel.clone());
+        std::thread::spawn(move || {
+            let probes: Vec<_> = regions.into_iter().map(|cdn| {
+                let (backend, channel) = (backend.clone(), channel.clone());
+                std::thread::spawn(move || {
+                    let res = backend.probe_cdn(&cdn, &channel);
+                    (cdn, res)
+                })
+            }).collect();
+            let _ = send.send(Msg::Probed(probes.into_iter().filter_map(|x| x.join().ok()).collect()));
+        });
+    }
+
+    /// How to install and run the selected channel, with `extra_args` after the channel's own and `env` set.
+    pub fn play_config(&self, extra_args: &[String], env: &[(String, String)]) -> Option<PlayGameConfig> {
+        let data = self.channel_data()?;
+        let mut args = data.args.clone();
+        args.extend(extra_args.iter().cloned());
+        Some(PlayGameConfig {
+            cdns: self.regions(),
+            channel: self.channel.clone(),
+            latest_build: data.newest_release_name.clone(),
+            args,
+            env: env.to_vec(),
+            wrapper: String::new()
+        })
+    }
+
+    /// How to run the installed `manifest` without the release server, with the channel's args from
+    /// the last time they were fetched, `extra_args` after them and `env` set.
+    pub fn offline_play_config(&self, manifest: &InstallManifest, extra_args: &[String], env: &[(String, String)]) -> PlayGameConfig {
+        let cached = self.cache.as_ref().and_then(|x| x.channel(&manifest.channel));
+        if cached.is_none() {
+            log::warn!("No cached args for channel {}, launching without them", manifest.channel);
+        }
+        let mut args = cached.map(|x| x.data.args).unwrap_or_default();
+        args.extend(extra_args.iter().cloned());
+        PlayGameConfig {
+            cdns: Vec::new(),
+            channel: manifest.channel.clone(),
+            latest_build: manifest.version.clone(),
+            args,
+            env: env.to_vec(),
+            wrapper: String::new()
+        }
+    }
+
+    fn start<F>(&mut self, task: Task, phase: Phase, work: F) -> Result<(), anyhow::Error>
+    where F: FnOnce(&dyn ReleaseBackend, &Progress, &CancelToken) -> Result<Event, anyhow::Error> + Send + 'static {
+        if let Some(t) = self.task {
+            return Err(anyhow::anyhow!("Cannot start {:?} while {:?} is running", task, t));
+        }
+        if self.playing.load(Ordering::Relaxed) > 0 {
+            return Err(anyhow::anyhow!("Cannot start {:?} while the game is running", task));
+        }
+        let cancel = CancelToken::new();
+        self.task = Some(task);
+        self.progress = Some(ProgressEvent::new(phase, None, 0, 0));
+        self.cancel = Some(cancel.clone());
+        let (backend, send) = (self.backend.clone(), self.send.clone());
+        std::thread::spawn(move || {
+            let sink = send.clone();
+            let progress = Progress::new(move |e| { let _ = sink.send(Msg::Progress(e)); });
+            let _ = send.send(Msg::Done(work(backend.as_ref(), &progress, &cancel)));
+        });
+        Ok(())
+    }
+
+    /// Returns how to start the game and report back with `Msg::Exited` once it exits.
+    fn launcher(&self) -> impl FnOnce(&PlayGameConfig, &InstallManifest, &std::path::Path) -> Result<(), anyhow::Error> + Send + 'static {
+        let (send, playing) = (self.send.clone(), self.playing.clone());
+        move |config, manifest, dir| {
+            let game = crate::patch::launch_game(config, manifest, dir)?;
+            playing.fetch_add(1, Ordering::Relaxed);
+            std::thread::spawn(move || {
+                let res = game.wait();
+                playing.fetch_sub(1, Ordering::Relaxed);
+                let _ = send.send(Msg::Exited(res));
+            });
+            Ok(())
+        }
+    }
+
+    fn update_task(&mut self, task: Task, dir: PathBuf, config: PlayGameConfig) -> Result<(), anyhow::Error> {
+        let launch = self.launcher();
+        self.start(task, Phase::Starting, move |backend, progress, cancel| {
+            let manifest = crate::patch::update_internal(backend, &dir, &config, progress, cancel)?
+                .ok_or_else(|| anyhow::Error::msg("Unable to load launch manifest"))?;
+            if task == Task::Launch {
+                launch(&config, &manifest, &dir)?;
+            }
+            Ok(Event::Updated(task, manifest))
+        })
+    }
+
+    /// Installs or updates the game at `dir` to the release in `config`.
+    pub fn update(&mut self, dir: PathBuf, config: PlayGameConfig) -> Result<(), anyhow::Error> {
+        self.update_task(Task::Update, dir, config)
+    }
+
+    /// Like `update`, then starts the game.
+    pub fn launch(&mut self, dir: PathBuf, config: PlayGameConfig) -> Result<(), anyhow::Error> {
+        self.update_task(Task::Launch, dir, config)
+    }
+
+    /// Starts the game installed at `dir` as it is, without checking for updates.
+    pub fn launch_offline(&mut self, dir: PathBuf, config: PlayGameConfig) -> Result<(), anyhow::Error> {
+        let launch = self.launcher();
+        self.start(Task::Launch, Phase::Starting, move |_, _, _| {
+            let manifest = crate::patch::get_installed_version(&dir)?
+                .ok_or_else(|| anyhow::anyhow!("Procelio is not installed at {:?}", dir.display()))?;
+            launch(&config, &manifest, &dir)?;
+            Ok(Event::Updated(Task::Launch, manifest))
+        })
+    }
+
+    pub fn verify(&mut self, dir: PathBuf) -> Result<(), anyhow::Error> {
+        let cdns = self.regions();
+        self.start(Task::Verify, Phase::Verifying, move |backend, progress, cancel| {
+            crate::patch::verify_internal(backend, &dir, &cdns, progress, cancel).map(Event::Verified)
+        })
+    }
+
+    /// Removes the game at `dir`. Half an uninstall is no use to anyone, so this ignores `cancel`.
+    pub fn uninstall(&mut self, dir: PathBuf) -> Result<(), anyhow::Error> {
+        self.start(Task::Uninstall, Phase::Uninstalling, move |_, progress, _| {
+            crate::patch::uninstall_internal(&dir, progress)
+                .map(|_| Event::Uninstalled)
+                .map_err(|e| anyhow::anyhow!(format!("Uninstallation failed: {:?}", &e)))
+        })
+    }
+
+    fn handle(&mut self, msg: Msg) -> Option<Event> {
+        match msg {
+            Msg::Config(res) => {
+                self.loading_config = false;
+                let res = res.and_then(|x| Cached::refresh(self.config.clone(), x)
+                    .ok_or_else(|| anyhow::anyhow!("Launcher config was not modified, but none is cached")));
+                match res {
+                    Ok(cfg) => {
+                        if !cfg.data.channels.contains(&self.channel) {
+                            self.channel = "prod".to_owned();
+                        }
+                        if self.cdn != AUTO_CDN && !cfg.data.cdn_regions.contains(&self.cdn) {
+                            self.cdn = DEFAULT_CDN.to_owned();
+                        }
+                        if let Some(cache) = &self.cache
+                            && let Err(e) = cache.save_config(&cfg) {
+                            log::warn!("Unable to cache launcher config: {e:?}");
+                        }
+                        self.config = Some(cfg);
+                        let channel = self.channel.clone();
+                        self.select_channel(&channel);
+                        self.ranked.clear();
+                        let cdn = self.cdn.clone();
+                        self.select_cdn(&cdn);
+                        Some(Event::ConfigLoaded)
+                    }
+                    Err(e) => {
+                        let event = self.failed(e);
+                        if !self.offline {
+                            self.config = None;
+                        }
+                        Some(event)
+                    }
+                }
+            }
+            Msg::Channel(channel, _) if channel != self.channel => None,
+            Msg::Channel(_, res) => {
+                self.loading_channel = false;
+                let res = res.and_then(|x| Cached::refresh(self.channel_data.clone(), x)
+                    .ok_or_else(|| anyhow::anyhow!("Channel data was not modified, but none is cached")));
+                match res {
+                    Ok(data) => {
+                        if let Some(cache) = &self.cache
+                            && let Err(e) = cache.save_channel(&self.channel, &data) {
+                            log::warn!("Unable to cache channel data: {e:?}");
+                        }
+                        self.channel_data = Some(data);
+                        Some(Event::ChannelLoaded)
+                    }
+                    Err(e) => Some(self.failed(e))
+                }
+            }
+            Msg::Probed(mut results) => {
+                self.probing = false;
+                results.sort_by_key(|(_, res)| res.as_ref().ok().copied().unwrap_or(Duration::MAX));
+                for (cdn, res) in &results {
+                    match res {
+                        Ok(t) => log::debug!("Region {cdn}: {} ms", t.as_millis()),
+                        Err(e) => log::debug!("Region {cdn}: {e}")
+                    }
+                }
+                self.ranked = results.into_iter().map(|x| x.0).collect();
+                Some(Event::CdnProbed)
+            }
+            Msg::Progress(_) if self.task.is_none() => None,
+            Msg::Progress(event) => {
+                if self.progress.as_ref().is_none_or(|x| x.phase != event.phase || x.file != event.file) {
+                    log::info!("{event}");
+                }
+                self.progress = Some(event.clone());
+                Some(Event::Progress(event))
+            }
+            Msg::Exited(res) => Some(match res {
+                Ok(exit) => Event::GameExited(exit),
+                Err(e) => Event::Failed(e)
+            }),
+            Msg::Done(res) => {
+                let task = self.task.take()?;
+                let cancelled = self.cancel.take().is_some_and(|x| x.is_cancelled());
+                self.progress = None;
+                Some(match res {
+                    Err(_) if cancelled => Event::Cancelled(task),
+                    res => res.unwrap_or_else(Event::Failed)
+                })
+            }
+        }
+    }
+
+    /// Reports a failed load, going offline if the server could not be reached at all. Otherwise the
+    /// cached channel data is dropped, since the server may be refusing it on purpose.
+    fn failed(&mut self, e: anyhow::Error) -> Event {
+        if !is_offline(&e) {
+            self.channel_data = None;
+            return Event::Failed(e);
+        }
+        log::warn!("Release server unreachable: {e}");
+        self.offline = true;
+        Event::Offline(e)
+    }
+
+    /// Applies everything that finished since the last call, without blocking.
+    pub fn poll(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+        while let Ok(msg) = self.recv.try_recv() {
+            events.extend(self.handle(msg));
+        }
+        events
+    }
+
+    /// Blocks until the next event, including progress. `None` once nothing is loading or running.
+    pub fn next_event(&mut self) -> Option<Event> {
+        while matches!(self.state(), CoreState::LoadingConfig | CoreState::LoadingChannel | CoreState::ProbingCdn | CoreState::Busy(_)) {
+            if let Some(event) = self.handle(self.recv.recv().ok()?) {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    /// Blocks until nothing is loading or running. Progress events are left out.
+    pub fn wait(&mut self) -> Vec<Event> {
+        std::iter::from_fn(|| self.next_event())
+            .filter(|x| !matches!(x, Event::Progress(_)))
+            .collect()
+    }
+}
+
+/// Whether `e` means the release server could not be reached, as opposed to it answering with an error.
+pub fn is_offline(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<crate::net::NetError>().is_some_and(|x| x.is_transient())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakeBackend;
+    use crate::json::{Patch, Release, UpgradePath};
+
+    fn release_zip(version: &str, channel: &str) -> Vec<u8> {
+        let manifest = format!(r#"{{"exec": "Procelio", "version": "{version}", "channel": "{channel}"}}"#);
+        FakeBackend::zip(&[("manifest.json", manifest.as_bytes()), ("Procelio", b""), ("Data/level.dat", version.as_bytes())])
+    }
+
+    fn release(channel: &str, name: &str) -> Release {
+        Release {
+            channel: channel.to_owned(),
+            platform: "linux".to_owned(),
+            name: name.to_owned(),
+            download_size: 0,
+            title: name.to_owned(),
+            description: String::new(),
+            changelog: String::new(),
+            sha256: None
+        }
+    }
+
+    fn fake() -> Arc<FakeBackend> {
+        let backend = FakeBackend::new(&["prod", "dev"], &["nyc3"]);
+        backend.set_channel("prod", "1.0.0", &["-prod"]);
+        backend.set_channel("dev", "1.1.0dev", &["-dev"]);
+        backend.add_release("nyc3", "prod", "1.0.0", release_zip("1.0.0", "prod"));
+        backend.add_release("nyc3", "dev", "1.1.0dev", release_zip("1.1.0dev", "dev"));
+        Arc::new(backend)
+    }
+
+    fn install_dir() -> (tempfile::TempDir, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("Procelio");
+        (root, dir)
+    }
+
+    fn ready(backend: Arc<FakeBackend>, channel: &str) -> LauncherCore {
+        let mut core = LauncherCore::new(backend, channel, "nyc3");
+        core.load_config();
+        let events = core.wait();
+        assert!(matches!(events[..], [Event::ConfigLoaded, Event::ChannelLoaded]), "{events:?}");
+        assert_eq!(core.state(), CoreState::Ready);
+        core
+    }
+
+    fn update(core: &mut LauncherCore, dir: &std::path::Path) -> Result<InstallManifest, anyhow::Error> {
+        let config = core.play_config(&[], &[]).unwrap();
+        core.update(dir.to_owned(), config)?;
+        match core.wait().pop() {
+            Some(Event::Updated(Task::Update, m)) => Ok(m),
+            Some(Event::Failed(e)) => Err(e),
+            e => panic!("unexpected {e:?}")
+        }
+    }
+
+    #[test]
+    fn test_fresh_install() {
+        let (_root, dir) = install_dir();
+        let backend = fake();
+        let mut core = ready(backend.clone(), "prod");
+
+        let manifest = update(&mut core, &dir).unwrap();
+        assert_eq!(manifest.version, "1.0.0");
+        assert_eq!(std::fs::read_to_string(dir.join("Data/level.dat")).unwrap(), "1.0.0");
+        assert_eq!(backend.downloads(), vec!["fake://nyc3/release/prod/1.0.0"]);
+        assert_eq!(core.state(), CoreState::Ready);
+        assert!(core.progress().is_none());
+
+        // Already up to date: nothing else is downloaded.
+        update(&mut core, &dir).unwrap();
+        assert_eq!(backend.downloads().len(), 1);
+    }
+
+    #[test]
+    fn test_fresh_install_checks_digest() {
+        let (_root, dir) = install_dir();
+        let backend = fake();
+        backend.set_release(Release { sha256: Some("0".repeat(64)), ..release("prod", "1.0.0") });
+        let mut core = ready(backend.clone(), "prod");
+
+        // The first install is checked too, and nothing of a corrupt release is extracted.
+        let err = update(&mut core, &dir).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(crate::net::NetError::Integrity { .. })), "{err:?}");
+        assert!(!dir.exists());
+        assert!(!dir.with_file_name("Procelio.staging").exists());
+    }
+
+    #[test]
+    fn test_fresh_install_without_release_info() {
+        let (_root, dir) = install_dir();
+        let backend = fake();
+        backend.remove_release("prod", "1.0.0");
+        let mut core = ready(backend.clone(), "prod");
+
+        // A server with no record of the release still installs it, just without the checks.
+        let manifest = update(&mut core, &dir).unwrap();
+        assert_eq!(manifest.version, "1.0.0");
+    }
+
+    #[test]
+    fn test_fresh_install_checks_space_first() {
+        let (_root, dir) = install_dir();
+        let backend = fake();
+        backend.set_release(Release { download_size: u64::MAX / 4, ..release("prod", "1.0.0") });
+        let mut core = ready(backend.clone(), "prod");
+
+        let err = update(&mut core, &dir).err().unwrap();
+        assert!(err.to_string().contains("Not enough disk space"), "{err:?}");
+        assert!(backend.downloads().is_empty());
+    }
+
+    #[test]
+    fn test_patch_route_checks_space_first() {
+        let (_root, dir) = install_dir();
+        let backend = fake();
+        let mut core = ready(backend.clone(), "prod");
+        update(&mut core, &dir).unwrap();
+
+        // The patch is small to download, but would not fit once applied.
+        let data = FakeBackend::zip(&[("Procelio.exe", b"patched")]);
+        backend.set_channel("prod", "1.0.1", &[]);
+        backend.add_patch("nyc3", "prod", "1.0.0-1.0.1", data.clone());
+        backend.set_unpacked_size("fake://nyc3/patch/prod/1.0.0-1.0.1", u64::MAX / 4);
+        backend.set_route("prod", "prod", "1.0.0", UpgradePath::PatchRoute(vec![patch("1.0.0-1.0.1", "1.0.0", "1.0.1", &data)]));
+        core.select_channel("prod");
+        core.wait();
+
+        let err = update(&mut core, &dir).err().unwrap();
+        assert!(err.to_string().contains("Not enough disk space"), "{err:?}");
+        assert_eq!(backend.downloads(), ["fake://nyc3/release/prod/1.0.0"]);
+        assert_eq!(crate::patch::get_installed_version(&dir).unwrap().unwrap().version, "1.0.0");
+    }
+
+    #[test]
+    fn test_progress_events() {
+        let (_root, dir) = install_dir();
+        let mut core = ready(fake(), "prod");
+        let config = core.play_config(&[], &[]).unwrap();
+        core.update(dir, config).unwrap();
+        assert_eq!(core.progress().map(|x| x.phase), Some(Phase::Starting));
+
+        let mut phases = Vec::new();
+        while let Some(event) = core.next_event() {
+            if let Event::Progress(p) = event {
+                assert!(p.done <= p.total || p.total == 0, "{p:?}");
+                if phases.last() != Some(&p.phase) {
+                    phases.push(p.phase);
+                }
+            }
+        }
+        assert_eq!(phases, vec![Phase::Extracting, Phase::Finishing]);
+        assert!(core.progress().is_none());
+    }
+
+    #[test]
+    fn test_auto_cdn_failover() {
+        let (_root, dir) = install_dir();
+        let backend = FakeBackend::new(&["prod"], &["nyc3", "sfo3", "ams3"]);
+        backend.set_channel("prod", "1.0.0", &[]);
+        for (cdn, ms) in [("nyc3", 80), ("sfo3", 10), ("ams3", 40)] {
+            backend.add_release(cdn, "prod", "1.0.0", release_zip("1.0.0", "prod"));
+            backend.set_latency(cdn, Duration::from_millis(ms));
+        }
+        backend.fail_downloads("sfo3");
+        let backend = Arc::new(backend);
+
+        let mut core = LauncherCore::new(backend.clone(), "prod", AUTO_CDN);
+        core.load_config();
+        let events = core.wait();
+        assert!(events.iter().any(|x| matches!(x, Event::CdnProbed)), "{events:?}");
+        assert_eq!(core.cdn(), AUTO_CDN);
+        assert_eq!(core.regions(), vec!["sfo3", "ams3", "nyc3"]);
+
+        // The fastest region drops the download, so it carries on from the next best.
+        update(&mut core, &dir).unwrap();
+        assert_eq!(backend.downloads(), vec!["fake://sfo3/release/prod/1.0.0", "fake://ams3/release/prod/1.0.0"]);
+
+        // A region picked by hand goes first, with the rest as fallbacks.
+        core.select_cdn("nyc3");
+        assert_eq!(core.regions(), vec!["nyc3", "sfo3", "ams3"]);
+    }
+
+    fn patch(name: &str, from: &str, to: &str, data: &[u8]) -> Patch {
+        Patch {
+            name: name.to_owned(),
+            download_size: data.len() as u64,
+            platform: "linux".to_owned(),
+            from_channel: "prod".to_owned(),
+            to_channel: "prod".to_owned(),
+            from_name: from.to_owned(),
+            to_name: to.to_owned(),
+            sha256: Some(hex::encode(<sha2::Sha256 as sha2::Digest>::digest(data)))
+        }
+    }
+
+    #[test]
+    fn test_patch_route_applies() {
+        let (_root, dir) = install_dir();
+        let backend = fake();
+        let mut core = ready(backend.clone(), "prod");
+        update(&mut core, &dir).unwrap();
+
+        // Each step only carries what changed.
+        let manifest = |version: &str| format!(r#"{{"exec": "Procelio", "version": "{version}", "channel": "prod"}}"#);
+        let first = FakeBackend::zip(&[("manifest.json", manifest("1.0.1").as_bytes()), ("Data/level.dat", b"1.0.1")]);
+        let second = FakeBackend::zip(&[("manifest.json", manifest("1.0.2").as_bytes()), ("Data/extra.dat", b"new")]);
+        backend.set_channel("prod", "1.0.2", &[]);
+        backend.add_patch("nyc3", "prod", "1.0.0-1.0.1", first.clone());
+        backend.add_patch("nyc3", "prod", "1.0.1-1.0.2", second.clone());
+        backend.set_route("prod", "prod", "1.0.0", UpgradePath::PatchRoute(vec![
+            patch("1.0.0-1.0.1", "1.0.0", "1.0.1", &first),
+            patch("1.0.1-1.0.2", "1.0.1", "1.0.2", &second)
+        ]));
+        core.select_channel("prod");
+        core.wait();
+
+        let manifest = update(&mut core, &dir).unwrap();
+        assert_eq!(manifest.version, "1.0.2");
+        assert_eq!(backend.downloads(), ["fake://nyc3/release/prod/1.0.0", "fake://nyc3/patch/prod/1.0.0-1.0.1", "fake://nyc3/patch/prod/1.0.1-1.0.2"]);
+        assert_eq!(crate::patch::get_installed_version(&dir).unwrap().unwrap().version, "1.0.2");
+        assert_eq!(std::fs::read_to_string(dir.join("Data/level.dat")).unwrap(), "1.0.1");
+        assert_eq!(std::fs::read_to_string(dir.join("Data/extra.dat")).unwrap(), "new");
+        assert!(dir.join("Procelio").is_file());
+        assert!(!dir.with_file_name("Procelio.staging").exists());
+        assert!(!dir.with_file_name("Procelio.old").exists());
+    }
+
+    #[test]
+    fn test_corrupt_patch_stops_route() {
+        let (_root, dir) = install_dir();
+        let backend = fake();
+        let mut core = ready(backend.clone(), "prod");
+        update(&mut core, &dir).unwrap();
+
+        let patch = |name: &str, from: &str, to: &str| Patch { download_size: 0, sha256: Some("0".repeat(64)), ..patch(name, from, to, b"") };
+        backend.set_channel("prod", "1.0.2", &[]);
+        backend.add_patch("nyc3", "prod", "1.0.0-1.0.1", b"not the advertised patch".to_vec());
+        backend.add_patch("nyc3", "prod", "1.0.1-1.0.2", b"never reached".to_vec());
+        backend.set_route("prod", "prod", "1.0.0", UpgradePath::PatchRoute(vec![
+            patch("1.0.0-1.0.1", "1.0.0", "1.0.1"),
+            patch("1.0.1-1.0.2", "1.0.1", "1.0.2")
+        ]));
+        core.select_channel("prod");
+        core.wait();
+
+        // Patches are fetched in route order, and a corrupt one stops the update before it touches the install.
+        let err = update(&mut core, &dir).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(crate::net::NetError::Integrity { .. })), "{err:?}");
+        assert_eq!(backend.downloads(), ["fake://nyc3/release/prod/1.0.0", "fake://nyc3/patch/prod/1.0.0-1.0.1"]);
+        assert_eq!(crate::patch::get_installed_version(&dir).unwrap().unwrap().version, "1.0.0");
+        assert_eq!(core.state(), CoreState::Ready);
+    }
+
+    #[test]
+    fn test_channel_switch() {
+        let (_root, dir) = install_dir();
+        let backend = fake();
+        let mut core = ready(backend.clone(), "prod");
+        update(&mut core, &dir).unwrap();
+
+        core.select_channel("dev");
+        assert_eq!(core.state(), CoreState::LoadingChannel);
+        core.wait();
+        assert_eq!(core.play_config(&["-x".to_owned()], &[]).unwrap().args, vec!["-dev", "-x"]);
+
+        backend.set_route("prod", "dev", "1.0.0", UpgradePath::FreshDownload(release("dev", "1.1.0dev")));
+        let manifest = update(&mut core, &dir).unwrap();
+        assert_eq!(manifest.channel, "dev");
+        assert_eq!(std::fs::read_to_string(dir.join("Data/level.dat")).unwrap(), "1.1.0dev");
+
+        // Unknown channels fall back to prod once the config arrives.
+        let core = ready(backend, "staging");
+        assert_eq!(core.channel(), "prod");
+    }
+
+    #[test]
+    fn test_uninstall() {
+        let (root, dir) = install_dir();
+        let mut core = ready(fake(), "prod");
+        update(&mut core, &dir).unwrap();
+
+        core.uninstall(dir.clone()).unwrap();
+        assert_eq!(core.state(), CoreState::Busy(Task::Uninstall));
+        assert!(core.verify(dir.clone()).is_err());
+        assert!(matches!(core.wait()[..], [Event::Uninstalled]));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // Refuses directories that might hold more than the game.
+        core.uninstall(root.path().to_owned()).unwrap();
+        assert!(matches!(core.wait()[..], [Event::Failed(_)]));
+    }
+
+    #[test]
+    fn test_offline_launch_config() {
+        let (root, dir) = install_dir();
+        let backend = fake();
+        let mut core = ready(backend.clone(), "dev");
+        core.set_cache(Some(Cache::new(root.path().join("cache"))));
+        core.select_channel("dev");
+        core.wait();
+        update(&mut core, &dir).unwrap();
+
+        backend.set_offline(true);
+        core.load_config();
+        let events = core.wait();
+        assert!(matches!(events[..], [Event::Offline(_)]), "{events:?}");
+        assert_eq!(core.state(), CoreState::Offline);
+        assert!(core.channel_data().is_some());
+
+        // The args fetched while online are still used.
+        let manifest = crate::patch::get_installed_version(&dir).unwrap().unwrap();
+        let config = core.offline_play_config(&manifest, &["-x".to_owned()], &[]);
+        assert_eq!((config.channel.as_str(), config.latest_build.as_str()), ("dev", "1.1.0dev"));
+        assert_eq!(config.args, vec!["-dev", "-x"]);
+
+        backend.set_offline(false);
+        core.load_config();
+        core.wait();
+        assert_eq!(core.state(), CoreState::Ready);
+    }
+
+    #[test]
+    fn test_cached_config() {
+        let root = tempfile::tempdir().unwrap();
+        let backend = fake();
+        let mut core = LauncherCore::new(backend.clone(), "prod", "nyc3");
+        core.set_cache(Some(Cache::new(root.path().to_owned())));
+        core.load_config();
+        assert!(core.config().is_none());
+        core.wait();
+
+        // A fresh start shows the cached data before the server answers, and the server only confirms it.
+        let mut core = LauncherCore::new(backend.clone(), "prod", "nyc3");
+        core.set_cache(Some(Cache::new(root.path().to_owned())));
+        core.load_config();
+        assert_eq!(core.state(), CoreState::LoadingConfig);
+        assert_eq!(core.channel_data().unwrap().newest_release_name, "1.0.0");
+        let events = core.wait();
+        assert!(matches!(events[..], [Event::ConfigLoaded, Event::ChannelLoaded]), "{events:?}");
+        assert_eq!(backend.not_modified(), 2);
+        assert!(core.fetched().is_some());
+
+        // Changed data replaces what was cached.
+        backend.set_channel("prod", "1.0.1", &[]);
+        core.load_config();
+        core.wait();
+        assert_eq!(core.channel_data().unwrap().newest_release_name, "1.0.1");
+        assert_eq!(backend.not_modified(), 3);
+        assert_eq!(Cache::new(root.path().to_owned()).channel("prod").unwrap().data.newest_release_name, "1.0.1");
+    }
+}
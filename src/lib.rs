@@ -3,11 +3,21 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
+pub mod backend;
+pub mod cache;
+pub mod cancel;
+pub mod cli;
 pub mod json;
+pub mod launcher;
+pub mod logging;
 pub mod net;
 pub mod patch;
+pub mod progress;
+pub mod throttle;
 pub mod files;
+pub mod game;
 pub mod defs;
+pub mod diagnostics;
 pub use app::ProcelioLauncher;
 
 // ----------------------------------------------------------------------------
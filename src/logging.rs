@@ -0,0 +1,73 @@
+//! The launcher's own log: a rotating file in the config dir, stderr, and the last lines kept for the "Show Logs" window.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use crate::files::RotatingFile;
+
+/// Past this the log moves to `launcher.1.log` and starts again.
+const MAX_LOG_SIZE: u64 = 5 * 1024 * 1024;
+/// Previous logs kept, one per launcher start unless a session is long enough to rotate.
+const KEEP_LOGS: usize = 5;
+/// Lines kept in memory for the logs window.
+const RECENT_LINES: usize = 1000;
+
+struct Logger {
+    file: Mutex<Option<RotatingFile>>,
+    recent: Mutex<VecDeque<String>>
+}
+
+static LOGGER: Logger = Logger { file: Mutex::new(None), recent: Mutex::new(VecDeque::new()) };
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // Only our own debug output is wanted; libraries are noisy below warnings.
+        metadata.level() <= log::max_level()
+            && (metadata.target().starts_with("procelio") || metadata.level() <= Level::Warn)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("{} {:<5} {}: {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"), record.level(), record.target(), record.args());
+        eprintln!("{line}");
+        if let Some(file) = self.file.lock().unwrap().as_mut()
+            && let Err(e) = file.write_line(&line) {
+            eprintln!("Unable to write launcher log: {e}");
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line);
+    }
+
+    fn flush(&self) { }
+}
+
+/// Where the launcher log is written, in the launcher's config dir.
+pub fn log_path() -> Option<PathBuf> {
+    platform_dirs::AppDirs::new(Some("Procelio Launcher"), true).map(|x| x.config_dir.join("logs").join("launcher.log"))
+}
+
+/// Sends every following `log` record to a new launcher log. `verbose` includes debug output.
+/// Only the first call has any effect.
+pub fn init(verbose: bool) {
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+    log::set_max_level(if verbose { LevelFilter::Debug } else { LevelFilter::Info });
+    match log_path().map(|x| RotatingFile::create(x, MAX_LOG_SIZE, KEEP_LOGS)) {
+        Some(Ok(file)) => *LOGGER.file.lock().unwrap() = Some(file),
+        Some(Err(e)) => log::warn!("Unable to create launcher log: {e}"),
+        None => log::warn!("No config directory for the launcher log")
+    }
+    log::info!("Procelio Launcher {} on {} {}", crate::defs::version(), std::env::consts::OS, std::env::consts::ARCH);
+}
+
+/// The most recent log lines, oldest first.
+pub fn recent() -> Vec<String> {
+    LOGGER.recent.lock().unwrap().iter().cloned().collect()
+}
@@ -1,7 +1,7 @@
 #![forbid(unsafe_code)]
 #![cfg_attr(not(debug_assertions), deny(warnings))] // Forbid warnings in release builds
 #![warn(clippy::all, rust_2018_idioms)]
-#![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] //Hide console window in release builds on Windows, this blocks stdout.
+#![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] //Hide console window in release builds on Windows, this blocks stdout. Scripts use procelio_launcher_cli.
 
 use eframe::egui;
 // When compiling natively:
@@ -11,6 +11,13 @@ fn main() {
 
     let icon = eframe::icon_data::from_png_bytes(include_bytes!("resources/procelio_icon.png")).unwrap();
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|x| procelio_launcher::cli::COMMANDS.contains(&x.as_str())) {
+        std::process::exit(procelio_launcher::cli::run(&args[1..]));
+    }
+    procelio_launcher::logging::init(args.iter().any(|x| x == "--verbose"));
+    let backend = procelio_launcher::net::BackendOverride::from_args(&args);
+
     let mut app = procelio_launcher::ProcelioLauncher::default();
     let mut native_options = eframe::NativeOptions::default();
     native_options.viewport.inner_size = Some(egui::vec2(960.0, 540.0));
@@ -20,11 +27,11 @@ fn main() {
     native_options.viewport.icon = Some(Arc::new(icon));
     native_options.hardware_acceleration = eframe::HardwareAcceleration::Preferred;
     native_options.renderer = eframe::Renderer::Wgpu;
-    println!("Result: {:?}", eframe::run_native(
+    log::info!("Result: {:?}", eframe::run_native(
         &app.launcher_name.clone(),
         native_options,
         Box::new(|cc| {
-            app.setup(cc);
+            app.setup(cc, backend);
             Ok(Box::new(app))
         })
     ));
@@ -1,13 +1,23 @@
 use reqwest::blocking;
-use crate::json::{LauncherConfig, ConfigResponse, UpgradePath};
+use crate::json::{LauncherConfig, ConfigResponse, Release, UpgradePath, FileManifest};
 use std::io::BufWriter;
 use std::io::Write;
 use std::sync::mpsc::Sender;
 use std::thread;
-use crate::files::LoadedFileSource;
-use std::boxed::Box;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::files::{LoadedFileSource, LoadingFileSource};
+use crate::progress::{Phase, Progress, ProgressEvent, Throughput};
 use std::io::Read;
-use anyhow::anyhow;
+use std::io::Seek;
+use sha2::{Digest, Sha256};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use reqwest::header;
+use reqwest::StatusCode;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 fn killswitch_text() -> &'static str {
     #[cfg(windows)]
@@ -25,20 +35,533 @@ fn platform() -> &'static str {
     { "linux" }
 }
 
-fn check_killswitch() -> anyhow::Result<()> {
-    let cert = reqwest::tls::Certificate::from_pem(crate::defs::KILLSWITCHCERT)?;
-    let client = reqwest::blocking::ClientBuilder::new()
+/// Why a request to the release server failed, split by whether trying again can help.
+#[derive(Debug)]
+pub enum NetError {
+    /// Timeouts, dropped connections and 5xx responses. Worth retrying.
+    Transient(String),
+    /// The server answered with a status that will not change on retry, e.g. 404.
+    Status { what: String, url: String, status: StatusCode },
+    /// The server answered, but the body was not what we expected.
+    BadResponse(String),
+    /// The killswitch check failed, so we are not talking to the real server.
+    Killswitch,
+    /// A download finished, but its SHA-256 does not match the one the server advertised.
+    Integrity { url: String, expected: String, actual: String },
+    /// The user cancelled the download.
+    Cancelled,
+    /// Local failures, e.g. disk errors while saving a download.
+    Other(String)
+}
+
+impl NetError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, NetError::Transient(_))
+    }
+}
+
+impl std::fmt::Display for NetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetError::Transient(e) => write!(f, "Network error: {e}"),
+            NetError::Status { what, url, status } => write!(f, "{what} failed: server returned {status} ({url})"),
+            NetError::BadResponse(e) => write!(f, "Unexpected response from server: {e}"),
+            NetError::Killswitch => write!(f, "Killswitch certificate not valid. Are you connecting to the real Procelio server?"),
+            NetError::Integrity { url, expected, actual } => write!(f, "Downloaded file {url} is corrupt: expected SHA-256 {expected}, got {actual}"),
+            NetError::Cancelled => write!(f, "{}", Cancelled),
+            NetError::Other(e) => write!(f, "{e}")
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
+
+impl From<reqwest::Error> for NetError {
+    fn from(e: reqwest::Error) -> Self {
+        if let Some(status) = e.status() {
+            let url = e.url().map(|x| x.to_string()).unwrap_or_default();
+            return NetError::from_status("Request", url, status);
+        }
+        // A body that stops arriving is reported as a decode error around the I/O error, so check for that first.
+        if e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() || (e.is_decode() && has_io_source(&e)) {
+            return NetError::Transient(format!("{e:?}"));
+        }
+        if e.is_decode() {
+            return NetError::BadResponse(e.to_string());
+        }
+        NetError::Other(format!("{e:?}"))
+    }
+}
+
+fn has_io_source(e: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(s) = source {
+        if s.is::<std::io::Error>() {
+            return true;
+        }
+        source = s.source();
+    }
+    false
+}
+
+impl From<Cancelled> for NetError {
+    fn from(_: Cancelled) -> Self {
+        NetError::Cancelled
+    }
+}
+
+impl From<std::io::Error> for NetError {
+    fn from(e: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        if let Some(inner) = e.get_ref().and_then(|x| x.downcast_ref::<reqwest::Error>())
+            && (inner.is_timeout() || inner.is_body() || inner.is_request()) {
+            return NetError::Transient(format!("{inner:?}"));
+        }
+        match e.kind() {
+            ErrorKind::TimedOut | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+            | ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe | ErrorKind::Interrupted => NetError::Transient(e.to_string()),
+            _ => NetError::Other(e.to_string())
+        }
+    }
+}
+
+impl NetError {
+    fn from_status(what: &str, url: String, status: StatusCode) -> NetError {
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT {
+            NetError::Transient(format!("{what} failed: server returned {status} ({url})"))
+        } else {
+            NetError::Status { what: what.to_owned(), url, status }
+        }
+    }
+}
+
+/// How hard to try before a request is reported as failed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first.
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Applied to connecting and to each read, so a stalled download fails instead of hanging.
+    pub timeout: Duration
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            timeout: Duration::from_secs(30)
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter, so many clients retrying after an outage do not all hit the server at once.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        use std::hash::BuildHasher;
+        let exp = self.base_delay.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+        let cap = exp.min(self.max_delay);
+        let random = std::collections::hash_map::RandomState::new().hash_one(attempt) % 1000;
+        cap / 2 + (cap / 2).mul_f64(random as f64 / 1000.0)
+    }
+}
+
+/// A request that is currently being retried, for display in the UI.
+#[derive(Clone, Debug)]
+pub struct RetryNotice {
+    pub what: String,
+    pub attempt: u32,
+    pub attempts: u32,
+    pub error: String
+}
+
+impl std::fmt::Display for RetryNotice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: retrying ({}/{})…", self.what, self.attempt, self.attempts)
+    }
+}
+
+/// How to reach the internet from a network that needs a proxy or inspects TLS.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxySettings {
+    /// e.g. `http://proxy:3128` or `socks5://proxy:1080`. Empty uses the system's proxy variables.
+    pub url: String,
+    pub username: String,
+    /// Never saved, so it has to be entered again each time the launcher starts.
+    #[serde(skip)]
+    pub password: String,
+    /// Comma-separated hosts to reach directly, in the same format as `NO_PROXY`.
+    pub no_proxy: String,
+    /// PEM files with extra root certificates to trust, e.g. the CA of a TLS-inspecting proxy.
+    pub ca_certs: Vec<PathBuf>
+}
+
+impl std::fmt::Debug for ProxySettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxySettings")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .field("password", &if self.password.is_empty() { "" } else { "<redacted>" })
+            .field("no_proxy", &self.no_proxy)
+            .field("ca_certs", &self.ca_certs)
+            .finish()
+    }
+}
+
+impl ProxySettings {
+    /// Routes `builder` through the proxy, if one is set.
+    fn apply_proxy(&self, builder: blocking::ClientBuilder) -> Result<blocking::ClientBuilder, NetError> {
+        let url = self.url.trim();
+        if url.is_empty() {
+            return Ok(builder);
+        }
+        let mut proxy = reqwest::Proxy::all(url)
+            .map_err(|e| NetError::Other(format!("Proxy URL {url:?} is not valid: {e}")))?
+            .no_proxy(reqwest::NoProxy::from_string(&self.no_proxy));
+        if !self.username.is_empty() {
+            proxy = proxy.basic_auth(&self.username, &self.password);
+        }
+        Ok(builder.proxy(proxy))
+    }
+
+    /// Adds `ca_certs` to the roots `builder` trusts.
+    fn apply_roots(&self, mut builder: blocking::ClientBuilder) -> Result<blocking::ClientBuilder, NetError> {
+        for path in &self.ca_certs {
+            let pem = std::fs::read(path).map_err(|e| NetError::Other(format!("Unable to read CA certificate {path:?}: {e}")))?;
+            let certs = reqwest::tls::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| NetError::Other(format!("CA certificate {path:?} is not valid PEM: {e}")))?;
+            if certs.is_empty() {
+                return Err(NetError::Other(format!("CA certificate {path:?} does not contain any certificates")));
+            }
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        Ok(builder)
+    }
+}
+
+/// A release server to use in place of production, e.g. staging or a local mock.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackendOverride {
+    pub url: String,
+    /// PEM file with the certificate the override server presents. It is pinned for
+    /// API requests and the killswitch check, the way production pins its own.
+    pub cert: Option<PathBuf>
+}
+
+impl BackendOverride {
+    pub const URL_VAR: &'static str = "PROCELIO_BACKEND_URL";
+    pub const CERT_VAR: &'static str = "PROCELIO_BACKEND_CERT";
+
+    /// Reads `PROCELIO_BACKEND_URL` and `PROCELIO_BACKEND_CERT`.
+    pub fn from_env() -> Option<BackendOverride> {
+        let url = std::env::var(Self::URL_VAR).ok().filter(|x| !x.trim().is_empty())?;
+        Some(BackendOverride {
+            url,
+            cert: std::env::var_os(Self::CERT_VAR).map(PathBuf::from)
+        })
+    }
+
+    /// Reads `--backend <url>` and `--backend-cert <path>` from command line arguments, up to any `--`.
+    pub fn from_args(args: &[String]) -> Option<BackendOverride> {
+        let args = &args[..args.iter().position(|x| x == "--").unwrap_or(args.len())];
+        let value = |flag: &str| args.iter().position(|x| x == flag).and_then(|i| args.get(i + 1)).cloned();
+        Some(BackendOverride {
+            url: value("--backend")?,
+            cert: value("--backend-cert").map(PathBuf::from)
+        })
+    }
+}
+
+/// The release server in use, and the certificate its killswitch must present.
+#[derive(Clone, Debug)]
+pub struct Backend {
+    pub url: String,
+    pub killswitch_url: String,
+    /// PEM certificate pinned for the killswitch check. `None` skips the check, and is only allowed for a server on this machine.
+    pub cert: Option<Vec<u8>>,
+    pub production: bool
+}
+
+impl Backend {
+    pub fn production() -> Backend {
+        Backend {
+            url: crate::defs::URL.to_owned(),
+            killswitch_url: crate::defs::KILLSWITCHURL.to_owned(),
+            cert: Some(crate::defs::KILLSWITCHCERT.to_vec()),
+            production: true
+        }
+    }
+
+    /// Fails unless `o` is an http(s) URL. Only a server on this machine may leave out the pinned
+    /// certificate, in which case it has no killswitch check.
+    pub fn from_override(o: &BackendOverride) -> Result<Backend, anyhow::Error> {
+        let url = o.url.trim().trim_end_matches('/');
+        if url == crate::defs::URL && o.cert.is_none() {
+            return Ok(Backend::production());
+        }
+        let loopback = match reqwest::Url::parse(url) {
+            Ok(u) if matches!(u.scheme(), "http" | "https") && u.has_host() => {
+                let host = u.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+                host.eq_ignore_ascii_case("localhost") || host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+            },
+            _ => return Err(anyhow::anyhow!("Backend override {:?} is not an http(s) URL", o.url))
+        };
+        if o.cert.is_none() && !loopback {
+            return Err(anyhow::anyhow!("Backend override {:?} needs a pinned certificate (--backend-cert or {})", o.url, BackendOverride::CERT_VAR));
+        }
+        let cert = match &o.cert {
+            Some(path) => {
+                let pem = std::fs::read(path).map_err(|e| anyhow::anyhow!("Unable to read backend certificate {:?}: {}", path, e))?;
+                reqwest::tls::Certificate::from_pem(&pem)?;
+                Some(pem)
+            },
+            None => None
+        };
+        Ok(Backend {
+            url: url.to_owned(),
+            killswitch_url: url.to_owned(),
+            cert,
+            production: false
+        })
+    }
+}
+
+static RETRY_POLICY: RwLock<Option<RetryPolicy>> = RwLock::new(None);
+static BACKEND: RwLock<Option<Backend>> = RwLock::new(None);
+static CLIENT: RwLock<Option<blocking::Client>> = RwLock::new(None);
+static API_CLIENT: RwLock<Option<blocking::Client>> = RwLock::new(None);
+static RETRY_NOTICE: Mutex<Option<RetryNotice>> = Mutex::new(None);
+static PROXY: RwLock<Option<ProxySettings>> = RwLock::new(None);
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn backend() -> Backend {
+    BACKEND.read().unwrap().clone().unwrap_or_else(Backend::production)
+}
+
+/// Points every following request at `backend`.
+pub fn set_backend(backend: Backend) {
+    if !backend.production {
+        log::warn!("Using non-production backend {}", backend.url);
+    }
+    *BACKEND.write().unwrap() = Some(backend);
+    *API_CLIENT.write().unwrap() = None;
+}
+
+fn base_url() -> String {
+    backend().url
+}
+
+pub fn retry_policy() -> RetryPolicy {
+    RETRY_POLICY.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Replaces the retry policy used by every request, and the shared client built from its timeout.
+pub fn set_retry_policy(policy: RetryPolicy) {
+    *RETRY_POLICY.write().unwrap() = Some(policy);
+    *CLIENT.write().unwrap() = None;
+    *API_CLIENT.write().unwrap() = None;
+}
+
+pub fn proxy() -> ProxySettings {
+    PROXY.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Sends every following request through `proxy`. Fails without changing anything if the
+/// proxy URL or a certificate is not valid.
+pub fn set_proxy(proxy: ProxySettings) -> Result<(), NetError> {
+    proxy.apply_roots(proxy.apply_proxy(blocking::ClientBuilder::new())?)?.build()?;
+    *PROXY.write().unwrap() = Some(proxy);
+    *CLIENT.write().unwrap() = None;
+    *API_CLIENT.write().unwrap() = None;
+    Ok(())
+}
+
+/// The most connections a single download may use.
+pub const MAX_CONNECTIONS: usize = 16;
+
+pub fn download_connections() -> usize {
+    CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// Sets how many range requests a large download may run at once. `1` downloads in a single stream.
+pub fn set_download_connections(connections: usize) {
+    CONNECTIONS.store(connections.clamp(1, MAX_CONNECTIONS), Ordering::Relaxed);
+}
+
+/// The most recent request that is being retried, if any is still in progress.
+pub fn retry_notice() -> Option<RetryNotice> {
+    RETRY_NOTICE.lock().unwrap().clone()
+}
+
+/// Timeouts and proxy shared by every client. Extra roots are left to the caller, since pinned clients must not trust them.
+fn client_builder() -> Result<blocking::ClientBuilder, NetError> {
+    let policy = retry_policy();
+    proxy().apply_proxy(blocking::ClientBuilder::new()
+        .connect_timeout(policy.timeout)
+        .timeout(policy.timeout))
+}
+
+/// The client shared by every download from the CDN.
+fn client() -> Result<blocking::Client, NetError> {
+    if let Some(c) = CLIENT.read().unwrap().as_ref() {
+        return Ok(c.clone());
+    }
+    let client = proxy().apply_roots(client_builder()?)?.build()?;
+    *CLIENT.write().unwrap() = Some(client.clone());
+    Ok(client)
+}
+
+/// The client shared by every request to the release server. An override backend
+/// with a certificate only trusts that certificate, not even `ProxySettings::ca_certs`.
+fn api_client() -> Result<blocking::Client, NetError> {
+    if let Some(c) = API_CLIENT.read().unwrap().as_ref() {
+        return Ok(c.clone());
+    }
+    let mut builder = client_builder()?;
+    let backend = backend();
+    builder = match (backend.production, &backend.cert) {
+        (false, Some(pem)) => builder
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(reqwest::tls::Certificate::from_pem(pem)?),
+        _ => proxy().apply_roots(builder)?
+    };
+    let client = builder.build()?;
+    *API_CLIENT.write().unwrap() = Some(client.clone());
+    Ok(client)
+}
+
+/// Runs `f` until it succeeds, fails with a permanent error, or runs out of attempts.
+pub fn with_retry<T>(what: &str, mut f: impl FnMut() -> Result<T, NetError>) -> Result<T, NetError> {
+    let policy = retry_policy();
+    let mut attempt = 1;
+    let res = loop {
+        match f() {
+            Err(e) if e.is_transient() && attempt < policy.attempts => {
+                log::warn!("{} failed ({}), retrying ({}/{})", what, e, attempt, policy.attempts);
+                *RETRY_NOTICE.lock().unwrap() = Some(RetryNotice {
+                    what: what.to_owned(),
+                    attempt,
+                    attempts: policy.attempts,
+                    error: e.to_string()
+                });
+                thread::sleep(policy.delay(attempt));
+                attempt += 1;
+            }
+            res => break res
+        }
+    };
+    let mut notice = RETRY_NOTICE.lock().unwrap();
+    if notice.as_ref().is_some_and(|x| x.what == what) {
+        *notice = None;
+    }
+    res
+}
+
+fn send(client: &blocking::Client, what: &str, url: &str) -> Result<blocking::Response, NetError> {
+    let resp = client.get(url).send()?;
+    if !resp.status().is_success() {
+        return Err(NetError::from_status(what, url.to_owned(), resp.status()));
+    }
+    Ok(resp)
+}
+
+fn get_text(what: &str, url: &str) -> Result<String, NetError> {
+    with_retry(what, || Ok(send(&api_client()?, what, url)?.text()?))
+}
+
+/// Reads an endpoint that answers with a download URL. Anything other than an absolute
+/// http(s) URL is rejected, so an error page is never downloaded and handed to the zip reader.
+fn get_url(what: &str, url: &str) -> Result<String, NetError> {
+    let body = get_text(what, url)?;
+    let body = body.trim();
+    match reqwest::Url::parse(body) {
+        Ok(u) if matches!(u.scheme(), "http" | "https") && u.has_host() => Ok(body.to_owned()),
+        _ => {
+            let preview: String = body.chars().take(100).collect();
+            Err(NetError::BadResponse(format!("{what}: expected a download URL, got {preview:?}")))
+        }
+    }
+}
+
+fn get_json<T: DeserializeOwned>(what: &str, url: &str) -> Result<T, NetError> {
+    let text = get_text(what, url)?;
+    serde_json::from_str(&text).map_err(|e| NetError::BadResponse(format!("{what}: {e}")))
+}
+
+/// The `ETag`/`Last-Modified` a response came with, so the next request can ask for it only if it changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validator {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>
+}
+
+impl Validator {
+    fn from_response(resp: &blocking::Response) -> Validator {
+        let header = |name| resp.headers().get(name).and_then(|x| x.to_str().ok()).map(|x| x.to_owned());
+        Validator { etag: header(header::ETAG), last_modified: header(header::LAST_MODIFIED) }
+    }
+}
+
+/// The answer to a conditional request.
+pub enum Fetched<T> {
+    Modified(T, Validator),
+    /// Unchanged since the validator that was sent, so the cached copy is still current.
+    NotModified
+}
+
+/// Like `get_json`, but sends `since` as `If-None-Match`/`If-Modified-Since` so an unchanged response is not sent again.
+fn get_json_since<T: DeserializeOwned>(what: &str, url: &str, since: Option<&Validator>) -> Result<Fetched<T>, NetError> {
+    with_retry(what, || {
+        let mut req = api_client()?.get(url);
+        if let Some(v) = since {
+            if let Some(etag) = &v.etag {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(modified) = &v.last_modified {
+                req = req.header(header::IF_MODIFIED_SINCE, modified);
+            }
+        }
+        let resp = req.send()?;
+        if since.is_some() && resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::NotModified);
+        }
+        if !resp.status().is_success() {
+            return Err(NetError::from_status(what, url.to_owned(), resp.status()));
+        }
+        let validator = Validator::from_response(&resp);
+        let data = serde_json::from_str(&resp.text()?).map_err(|e| NetError::BadResponse(format!("{what}: {e}")))?;
+        Ok(Fetched::Modified(data, validator))
+    })
+}
+
+fn check_killswitch() -> Result<(), NetError> {
+    let backend = backend();
+    let cert = match &backend.cert {
+        Some(pem) => reqwest::tls::Certificate::from_pem(pem)?,
+        None => {
+            log::warn!("No certificate for backend {}, skipping killswitch check", backend.url);
+            return Ok(());
+        }
+    };
+    // Goes through the proxy, but never trusts its extra roots: the point is to pin our own certificate.
+    let client = proxy().apply_proxy(reqwest::blocking::ClientBuilder::new())?
         .tls_built_in_root_certs(false)
         .add_root_certificate(cert)
         .build()?;
-    let x = client.get(format!("{}/killswitch", crate::defs::KILLSWITCHURL)).send();
+    let x = client.get(format!("{}/killswitch", backend.killswitch_url)).send();
 
     match x {
         Ok(_) => Ok(()),
         Err(e) if e.is_connect() => {
             let format = format!("{:?}", e);
             if format.contains(killswitch_text()) {
-                Err(anyhow!("Killswitch certificate not valid. Are you connecting to the real Procelio server?"))
+                Err(NetError::Killswitch)
             } else {
                 Ok(())
             }
@@ -47,39 +570,36 @@ fn check_killswitch() -> anyhow::Result<()> {
     }
 }
 
-fn fetch_config() -> anyhow::Result<LauncherConfig> {
-    let res = blocking::get(format!("{}/v1/launcher/config", crate::defs::URL));
-    res.and_then(|x| x.json::<LauncherConfig>()).map_err(|x|x.into())
+fn fetch_config(since: Option<Validator>) -> Result<Fetched<LauncherConfig>, NetError> {
+    get_json_since("Fetching launcher config", &format!("{}/v1/launcher/config", base_url()), since.as_ref())
 }
 
-pub fn get_config(send: Sender<Result<LauncherConfig, anyhow::Error>>) {
-    thread::spawn(move || {
-        let a = thread::spawn(check_killswitch);
-        let b = thread::spawn(fetch_config);
+/// Fetches the launcher config unless it is unchanged since `since`, failing if the killswitch is engaged.
+pub fn load_config(since: Option<&Validator>) -> Result<Fetched<LauncherConfig>, NetError> {
+    let a = thread::spawn(check_killswitch);
+    let since = since.cloned();
+    let b = thread::spawn(move || fetch_config(since));
 
-        let killswitch = a.join().unwrap();
-        if killswitch.is_err() {
-            eprintln!("ERROR: {:?}", killswitch);
-            send.send(Err(killswitch.err().unwrap())).unwrap();
-            return;
-        }
-
-        send.send(b.join().unwrap()).unwrap();
-    });
+    let killswitch = a.join().unwrap();
+    if let Err(e) = killswitch {
+        log::error!("Loading config: {e:#}");
+        return Err(e);
+    }
+    b.join().unwrap()
 }
 
-pub fn get_data(channel: String, send: Sender<Result<ConfigResponse, anyhow::Error>>) {
-    thread::spawn(move || {
-        let res = blocking::get(format!("{}/v1/launcher/config/{channel}/{}", crate::defs::URL, platform()));
-        println!("Data: {:?}", res);
-        let res = res.and_then(|x| x.json::<ConfigResponse>());
-        send.send(res.map_err(|x|x.into())).unwrap();
-    });
+/// Fetches the data for `channel` unless it is unchanged since `since`.
+pub fn load_channel_data(channel: &str, since: Option<&Validator>) -> Result<Fetched<ConfigResponse>, NetError> {
+    let res = get_json_since::<ConfigResponse>("Fetching channel data", &format!("{}/v1/launcher/config/{channel}/{}", base_url(), platform()), since);
+    if let Ok(Fetched::Modified(data, _)) = &res {
+        log::debug!("Data: {:?}", data);
+    }
+    res
 }
 
-pub fn get_image(curr: String, image: String, send: Sender<Result<Vec<u8>, anyhow::Error>>) {
+pub fn get_image(cdn: String, curr: String, image: String, send: Sender<Result<Vec<u8>, anyhow::Error>>) {
     thread::spawn(move || {
-        let data = load_image(curr, image);
+        let data = load_image(&cdn, curr, image);
         let data = match data {
             Some(s) => Ok(s),
             None => Err(anyhow::anyhow!("missing image"))
@@ -88,129 +608,577 @@ pub fn get_image(curr: String, image: String, send: Sender<Result<Vec<u8>, anyho
     });
 }
 
-pub fn get_latest_build(channel: &str) -> Result<String, anyhow::Error> {
-    Ok(blocking::get(format!("{}/v1/latest/{channel}/{}", crate::defs::URL, platform()))?.text()?)
+pub fn get_latest_build(channel: &str) -> Result<String, NetError> {
+    get_text("Fetching latest build", &format!("{}/v1/latest/{channel}/{}", base_url(), platform()))
 }
 
-pub fn get_stat_url(cdn: &str, channel: &str) -> Result<String, anyhow::Error> {
-    Ok(blocking::get(format!("{}/v1/paths/stats/{cdn}/{channel}", crate::defs::URL))?.text()?)
+pub fn get_stat_url(cdn: &str, channel: &str) -> Result<String, NetError> {
+    get_url(&format!("Stats path (channel '{channel}', cdn '{cdn}')"), &format!("{}/v1/paths/stats/{cdn}/{channel}", base_url()))
 }
 
-pub fn get_release_url(cdn: &str, channel: &str, name: &str) -> Result<String, anyhow::Error> {
-    Ok(blocking::get(format!("{}/v1/paths/release/{cdn}/{channel}/{}/{name}", crate::defs::URL, platform()))?.text()?)
+/// How long the stats file on `cdn` takes to arrive, as a measure of how close the region is.
+pub fn probe_cdn(cdn: &str, channel: &str) -> Result<Duration, NetError> {
+    let url = get_stat_url(cdn, channel)?;
+    let start = std::time::Instant::now();
+    send(&client()?, "Probing download region", &url)?.bytes()?;
+    Ok(start.elapsed())
 }
 
-pub fn get_patch_url(cdn: &str, channel: &str, name: &str) -> Result<String, anyhow::Error> {
-    Ok(blocking::get(format!("{}/v1/paths/patch/{cdn}/{channel}/{}/{name}", crate::defs::URL, platform()))?.text()?)
+pub fn get_release_url(cdn: &str, channel: &str, name: &str) -> Result<String, NetError> {
+    get_url(&format!("Release path for '{name}' (channel '{channel}', platform '{}', cdn '{cdn}')", platform()), &format!("{}/v1/paths/release/{cdn}/{channel}/{}/{name}", base_url(), platform()))
 }
 
-pub fn get_image_url(cdn: &str, image: &str) -> Result<String, anyhow::Error> {
-    Ok(blocking::get(format!("{}/v1/paths/image/{cdn}/{image}", crate::defs::URL))?.text()?)
+pub fn get_patch_url(cdn: &str, channel: &str, name: &str) -> Result<String, NetError> {
+    get_url(&format!("Patch path for '{name}' (channel '{channel}', platform '{}', cdn '{cdn}')", platform()), &format!("{}/v1/paths/patch/{cdn}/{channel}/{}/{name}", base_url(), platform()))
 }
 
-pub fn get_update_path(from_channel: &str, to_channel: &str, from_release: &str) -> Result<UpgradePath, anyhow::Error> {
-    let res = blocking::get(format!("{}/v1/route/{to_channel}/{}/{from_release}/{from_channel}", crate::defs::URL, platform()));
-    Ok(res.and_then(|x| x.json::<UpgradePath>())?)
+pub fn get_image_url(cdn: &str, image: &str) -> Result<String, NetError> {
+    get_url(&format!("Image path for '{image}' (cdn '{cdn}')"), &format!("{}/v1/paths/image/{cdn}/{image}", base_url()))
 }
 
-pub fn get_launcher_url(cdn: &str, name: &str) -> Result<String, anyhow::Error> {
-    Ok(blocking::get(format!("{}/v1/paths/launcher/{cdn}/{name}", crate::defs::URL))?.text()?)
+pub fn get_file_manifest_url(cdn: &str, channel: &str, name: &str) -> Result<String, NetError> {
+    get_url(&format!("File manifest path for '{name}' (channel '{channel}', platform '{}', cdn '{cdn}')", platform()), &format!("{}/v1/paths/files/{cdn}/{channel}/{}/{name}", base_url(), platform()))
 }
 
-fn download_to_buffer<T: Write>(size: usize, mut read: reqwest::blocking::Response, write: T, status: Option<std::sync::Arc<std::sync::Mutex<(f32, String, Option<Box<anyhow::Error>>)>>>) -> Result<(), anyhow::Error>{
-    let mut writer = std::io::BufWriter::new(write);
+/// Fetches the list of files, sizes and hashes that make up release `name`.
+pub fn get_file_manifest(cdn: &str, channel: &str, name: &str) -> Result<FileManifest, anyhow::Error> {
+    let url = get_file_manifest_url(cdn, channel, name)?;
+    let reader = download_file(None, &url, None, None, &Progress::default(), None)?.as_reader();
+    serde_json::from_reader(reader).map_err(|e| NetError::BadResponse(format!("File manifest for '{name}': {e}")).into())
+}
+
+/// Fetches the size and digest of release `name`.
+pub fn get_release(channel: &str, name: &str) -> Result<Release, NetError> {
+    get_json("Fetching release info", &format!("{}/v1/release/{channel}/{}/{name}", base_url(), platform()))
+}
+
+pub fn get_update_path(from_channel: &str, to_channel: &str, from_release: &str) -> Result<UpgradePath, NetError> {
+    get_json("Fetching update route", &format!("{}/v1/route/{to_channel}/{}/{from_release}/{from_channel}", base_url(), platform()))
+}
+
+pub fn get_launcher_url(cdn: &str, name: &str) -> Result<String, NetError> {
+    get_url(&format!("Launcher path for '{name}' (platform '{}', cdn '{cdn}')", platform()), &format!("{}/v1/paths/launcher/{cdn}/{name}", base_url()))
+}
+
+/// How many times a download whose digest does not match is fetched from scratch.
+const INTEGRITY_ATTEMPTS: u32 = 2;
+
+/// How often a download reports its progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
 
-    let iter = (size / 100) as u64;
+/// How far back the reported download speed looks.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
 
+fn transfer_event(file: &str, done: u64, size: u64, rate: Option<f64>) -> ProgressEvent {
+    let eta = match rate {
+        Some(r) if r > 0. && size > done => Some(Duration::from_secs_f64((size - done) as f64 / r)),
+        _ => None
+    };
+    ProgressEvent { rate, eta, ..ProgressEvent::new(Phase::Downloading, Some(file.to_owned()), done, size) }
+}
+
+fn download_to_buffer<T: Write>(start: u64, size: u64, mut read: reqwest::blocking::Response, write: T, hasher: &mut Sha256, file: &str, progress: &Progress, cancel: Option<&CancelToken>) -> Result<(), NetError>{
+    let mut writer = std::io::BufWriter::new(write);
     let mut buf = vec![0; 65536];
 
-    
-    let mut k = 0;
-    let mut m = 0;
+    let mut speed = Throughput::new(THROUGHPUT_WINDOW);
+    let mut rate = speed.sample(start);
+    let mut reported = std::time::Instant::now();
+    let mut done = start;
     loop {
+        if let Some(c) = cancel {
+            let waited = std::time::Instant::now();
+            c.checkpoint()?;
+            // Time spent paused says nothing about the connection.
+            if waited.elapsed() > PROGRESS_INTERVAL {
+                speed.reset();
+            }
+        }
         let n = read.read(&mut buf)?;
         if n == 0 {
             break;
         }
 
         writer.write_all(&buf[0..n])?;
+        hasher.update(&buf[0..n]);
+        done += n as u64;
+        crate::throttle::throttle(n as u64, cancel);
 
-        let n = n as u64;
-        k += n;
-        m += n;
-        
-        if k > iter {
-            k -= iter;
-            let val = m as f32 / size as f32;
-            let status = status.clone();
-            thread::spawn(move || {
-                if let Some(s) = &status {
-                    let mut lock = s.lock().unwrap();
-                    lock.0 = lock.0.max(val);
-                }
-            });
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            reported = std::time::Instant::now();
+            rate = speed.sample(done);
+            progress.emit(transfer_event(file, done, size, rate));
         }
     }
 
+    writer.flush()?;
+    progress.emit(transfer_event(file, done, size, rate));
     Ok(())
 }
 
+/// The sidecar file holding the `ETag`/`Last-Modified` validator for a partial download.
+fn validator_path(partial: &Path) -> PathBuf {
+    let mut name = partial.as_os_str().to_owned();
+    name.push(".etag");
+    PathBuf::from(name)
+}
 
-pub fn download_file(exp_size: Option<u64>, url: &str, status: Option<std::sync::Arc<std::sync::Mutex<(f32, String, Option<Box<anyhow::Error>>)>>>)  -> Result<LoadedFileSource, anyhow::Error>{
-    if let Some(s) = &status {
-        let mut lock = s.lock().unwrap();
-        // https://host/bucket/file/name.name?awspresign
-        let reg = regex::Regex::new("^.*/([^/\\?]*)\\?")?;
-        if let Some(ss) = reg.captures(url) {
-            lock.1 = format!("Downloading file {}", ss.get(1).unwrap().as_str());
+/// The sidecar file recording which ranges of a chunked download have arrived.
+fn chunks_path(partial: &Path) -> PathBuf {
+    let mut name = partial.as_os_str().to_owned();
+    name.push(".chunks");
+    PathBuf::from(name)
+}
+
+/// Removes a partial download and its sidecars once its contents have been consumed.
+pub fn remove_partial(partial: &Path) -> Result<(), anyhow::Error> {
+    for path in [partial.to_path_buf(), validator_path(partial), chunks_path(partial)] {
+        if path.is_file() {
+            std::fs::remove_file(path)?;
         }
     }
+    Ok(())
+}
 
-    let mut resp = blocking::get(url)?;
-    let exp_size = exp_size.or(resp.content_length());
+/// Picks the value to send back in `If-Range`. Weak ETags are not allowed there, so fall back to `Last-Modified`.
+fn response_validator(resp: &blocking::Response) -> Option<String> {
+    let headers = resp.headers();
+    let etag = headers.get(header::ETAG).and_then(|x| x.to_str().ok()).filter(|x| !x.starts_with("W/"));
+    let modified = headers.get(header::LAST_MODIFIED).and_then(|x| x.to_str().ok());
+    etag.or(modified).map(|x| x.to_owned())
+}
+
+/// The total length from a `Content-Range: bytes a-b/total` or `bytes */total` header.
+fn content_range_total(resp: &blocking::Response) -> Option<u64> {
+    let range = resp.headers().get(header::CONTENT_RANGE)?.to_str().ok()?;
+    range.rsplit('/').next()?.trim().parse().ok()
+}
+
+/// The first byte offset from a `Content-Range: bytes a-b/total` header.
+fn content_range_start(resp: &blocking::Response) -> Option<u64> {
+    let range = resp.headers().get(header::CONTENT_RANGE)?.to_str().ok()?;
+    range.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+/// Hashes the bytes already downloaded by a previous attempt, so a resumed download still gets a full digest.
+fn hash_existing(partial: &Path) -> Result<Sha256, NetError> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(partial)?, &mut hasher)?;
+    Ok(hasher)
+}
+
+/// Makes a single attempt at bringing `partial` up to date with `url`, continuing from whatever is
+/// already on disk. Returns the hex SHA-256 of the complete file.
+fn download_partial(exp_size: Option<u64>, url: &str, partial: &Path, progress: &Progress, cancel: Option<&CancelToken>) -> Result<String, NetError> {
+    let have = partial.metadata().map(|x| x.len()).unwrap_or(0);
+    let validator = std::fs::read_to_string(validator_path(partial)).ok();
+
+    let mut req = client()?.get(url);
+    if let (true, Some(v)) = (have > 0, &validator) {
+        req = req.header(header::RANGE, format!("bytes={have}-")).header(header::IF_RANGE, v.trim());
+    }
+    let resp = req.send()?;
+
+    if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE && content_range_total(&resp) == Some(have) {
+        log::info!("Partial download {:?} already complete", partial);
+        return Ok(hex::encode(hash_existing(partial)?.finalize()));
+    }
+
+    if !resp.status().is_success() {
+        return Err(NetError::from_status("Download", url.to_owned(), resp.status()));
+    }
+    let resumed = resp.status() == StatusCode::PARTIAL_CONTENT && content_range_start(&resp) == Some(have);
+
+    let (start, file, mut hasher) = if resumed {
+        log::info!("Resuming {:?} from byte {}", partial, have);
+        (have, std::fs::OpenOptions::new().append(true).open(partial)?, hash_existing(partial)?)
+    } else {
+        if have > 0 {
+            log::info!("Restarting {:?}: the remote file changed or ranges are unsupported", partial);
+        }
+        match response_validator(&resp) {
+            Some(v) => std::fs::write(validator_path(partial), v)?,
+            None => {
+                let _ = std::fs::remove_file(validator_path(partial));
+            }
+        }
+        (0, std::fs::File::create(partial)?, Sha256::new())
+    };
+
+    let size = if resumed {
+        content_range_total(&resp)
+    } else {
+        resp.content_length()
+    }.or(exp_size).unwrap_or(0);
+
+    download_to_buffer(start, size, resp, file, &mut hasher, &file_name(url), progress, cancel)?;
 
-    if let None = exp_size {
-        let file = tempfile::tempfile()?;
-        let mut writer = std::io::BufWriter::new(file.try_clone()?);
-        resp.copy_to(&mut writer)?;
-        return Ok(LoadedFileSource::OnDisk(file));
+    let len = partial.metadata()?.len();
+    if size > 0 && len != size {
+        return Err(NetError::Transient(format!("Download of {} ended early ({} of {} bytes)", url, len, size)));
     }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Downloads smaller than this always use a single connection.
+const CHUNKED_MIN_SIZE: u64 = 32 * 1024 * 1024;
 
-    let reader = resp;
+/// How far a chunked download has got, saved next to the partial file so it can resume.
+#[derive(Serialize, Deserialize)]
+struct Chunks {
+    validator: Option<String>,
+    size: u64,
+    /// `(start, end, done)` for each range, with `end` exclusive.
+    ranges: Vec<(u64, u64, u64)>
+}
 
-    let size = exp_size.unwrap();
-    if size < 512_000_000 {
-        let mut buf = vec![0u8; size as usize];
-        let cs = std::io::Cursor::new(&mut buf);
-        download_to_buffer(size as usize, reader, cs, status)?;
-        return Ok(LoadedFileSource::InMemory(buf));
+impl Chunks {
+    /// Picks up a saved chunked download of the same file.
+    fn load(partial: &Path, size: u64, validator: &Option<String>) -> Option<Chunks> {
+        std::fs::read(chunks_path(partial)).ok()
+            .and_then(|x| serde_json::from_slice::<Chunks>(&x).ok())
+            .filter(|x| x.size == size && x.validator == *validator && partial.metadata().is_ok_and(|m| m.len() == size))
     }
 
-    let file = tempfile::tempfile()?;
-    let f2 = file.try_clone()?;
-    download_to_buffer(size as usize, reader, BufWriter::new(f2), status)?;
-    Ok(LoadedFileSource::OnDisk(file))
+    /// Splits what is left of `size` after the first `have` bytes into `connections` new ranges.
+    fn new(size: u64, validator: &Option<String>, have: u64, connections: usize) -> Chunks {
+        let step = (size - have).div_ceil(connections as u64).max(1);
+        let ranges = (have > 0).then_some((0, have, have)).into_iter()
+            .chain((have..size).step_by(step as usize).map(|start| (start, (start + step).min(size), 0)))
+            .collect();
+        Chunks { validator: validator.clone(), size, ranges }
+    }
+
+    fn save(&self, partial: &Path) -> Result<(), NetError> {
+        let data = serde_json::to_vec(self).map_err(|e| NetError::Other(e.to_string()))?;
+        Ok(std::fs::write(chunks_path(partial), data)?)
+    }
+
+    fn done(&self) -> u64 {
+        self.ranges.iter().map(|x| x.2).sum()
+    }
 }
 
-pub fn load_image(curr_name: String, image_name: String) -> Option<Vec<u8>> {
-    let mut path = match platform_dirs::AppDirs::new(Some("Procelio Launcher"), true) {
-        None => { return None; }
-        Some(s) => s.config_dir
+/// Fetches what is left of range `index` of a chunked download into its place in `partial`.
+/// Returns early, without an error, once `stop` is set because another range failed.
+fn download_range(url: &str, partial: &Path, index: usize, chunks: &Mutex<Chunks>, done: &AtomicU64, stop: &AtomicBool, cancel: Option<&CancelToken>) -> Result<(), NetError> {
+    let ((start, end, have), validator) = {
+        let c = chunks.lock().unwrap();
+        (c.ranges[index], c.validator.clone())
     };
+    let mut pos = start + have;
+    if pos >= end {
+        return Ok(());
+    }
+
+    let mut req = client()?.get(url).header(header::RANGE, format!("bytes={}-{}", pos, end - 1));
+    if let Some(v) = &validator {
+        req = req.header(header::IF_RANGE, v.trim());
+    }
+    let mut resp = req.send()?;
+    if !resp.status().is_success() {
+        return Err(NetError::from_status("Download", url.to_owned(), resp.status()));
+    }
+    if resp.status() != StatusCode::PARTIAL_CONTENT || content_range_start(&resp) != Some(pos) {
+        return Err(NetError::BadResponse(format!("{} changed on the server during the download", file_name(url))));
+    }
+
+    let mut file = std::fs::OpenOptions::new().write(true).open(partial)?;
+    file.seek(std::io::SeekFrom::Start(pos))?;
+    let mut buf = vec![0; 65536];
+    while pos < end {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        if let Some(c) = cancel {
+            c.checkpoint()?;
+        }
+        let want = buf.len().min((end - pos) as usize);
+        let n = resp.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(NetError::Transient(format!("Download of {} ended early (range {}-{})", url, start, end)));
+        }
+        file.write_all(&buf[..n])?;
+        pos += n as u64;
+        chunks.lock().unwrap().ranges[index].2 += n as u64;
+        done.fetch_add(n as u64, Ordering::Relaxed);
+        crate::throttle::throttle(n as u64, cancel);
+    }
+    Ok(())
+}
+
+/// Asks for the first byte of `url` to learn its size and validator. Returns `None` if the server
+/// does not support ranges, or reports a size other than `exp_size`, so the caller can use a single stream.
+fn probe_ranges(exp_size: Option<u64>, url: &str) -> Result<Option<(u64, Option<String>)>, NetError> {
+    let probe = with_retry("Downloading", || Ok(client()?.get(url).header(header::RANGE, "bytes=0-0").send()?))?;
+    match content_range_total(&probe) {
+        Some(size) if probe.status() == StatusCode::PARTIAL_CONTENT && exp_size.is_none_or(|x| x == size) =>
+            Ok(Some((size, response_validator(&probe)))),
+        _ => {
+            log::info!("{} does not support range requests", file_name(url));
+            Ok(None)
+        }
+    }
+}
+
+/// A file on a server that supports range requests, fetched a piece at a time as it is read.
+struct RemoteFile {
+    client: blocking::Client,
+    url: String,
+    size: u64,
+    pos: u64
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let (pos, end) = (self.pos, self.size.min(self.pos + buf.len() as u64));
+        let data = with_retry("Reading archive contents", || {
+            let resp = self.client.get(&self.url).header(header::RANGE, format!("bytes={}-{}", pos, end - 1)).send()?;
+            if resp.status() != StatusCode::PARTIAL_CONTENT || content_range_start(&resp) != Some(pos) {
+                return Err(NetError::BadResponse(format!("{} stopped answering range requests", file_name(&self.url))));
+            }
+            Ok(resp.bytes()?)
+        }).map_err(std::io::Error::other)?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(x) => Some(x),
+            std::io::SeekFrom::End(x) => self.size.checked_add_signed(x),
+            std::io::SeekFrom::Current(x) => self.pos.checked_add_signed(x)
+        };
+        self.pos = pos.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the file"))?;
+        Ok(self.pos)
+    }
+}
+
+/// What a zip archive holds, read from its central directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ZipListing {
+    /// Size of the archive itself.
+    pub size: u64,
+    /// Total size of the files in it once extracted.
+    pub unpacked_size: u64,
+    pub names: Vec<String>
+}
+
+impl ZipListing {
+    /// Reads the listing of the `size` byte archive in `reader`. `None` if the archive does not
+    /// record its sizes up front, as a streamed zip may not.
+    pub fn read<R: Read + Seek>(size: u64, reader: R) -> Result<Option<ZipListing>, zip::result::ZipError> {
+        let zip = zip::ZipArchive::new(reader)?;
+        Ok(zip.decompressed_size().and_then(|x| u64::try_from(x).ok()).map(|unpacked_size| ZipListing {
+            size,
+            unpacked_size,
+            names: zip.file_names().map(|x| x.to_owned()).collect()
+        }))
+    }
+}
+
+/// Reads the listing of the zip at `url` with range requests, without downloading the rest of it.
+/// `None` if the server does not support ranges or the listing cannot be read.
+pub fn zip_listing(url: &str) -> Result<Option<ZipListing>, NetError> {
+    let Some((size, _)) = probe_ranges(None, url)? else {
+        return Ok(None);
+    };
+    let file = RemoteFile { client: client()?, url: url.to_owned(), size, pos: 0 };
+    match ZipListing::read(size, std::io::BufReader::with_capacity(65536, file)) {
+        Ok(listing) => Ok(listing),
+        Err(e) => {
+            log::warn!("Unable to read the contents of {} before downloading it: {e}", file_name(url));
+            Ok(None)
+        }
+    }
+}
+
+/// Downloads `url`, found by [`probe_ranges`] to be `size` bytes, into `partial` over up to
+/// `connections` range requests at once, each retried on its own.
+fn download_chunked(size: u64, validator: Option<String>, url: &str, partial: &Path, connections: usize, progress: &Progress, cancel: Option<&CancelToken>) -> Result<String, NetError> {
+    let chunks = match Chunks::load(partial, size, &validator) {
+        Some(chunks) => {
+            log::info!("Resuming {:?} from {} of {} bytes", partial, chunks.done(), size);
+            chunks
+        }
+        None => {
+            // A single-stream download of the same file carries on as the first range.
+            let saved = std::fs::read_to_string(validator_path(partial)).ok();
+            let have = match (&validator, saved) {
+                (Some(v), Some(s)) if v.trim() == s.trim() => partial.metadata().map(|x| x.len().min(size)).unwrap_or(0),
+                _ => 0
+            };
+            if have > 0 {
+                log::info!("Resuming {:?} from byte {} over {} connections", partial, have, connections);
+            }
+            std::fs::OpenOptions::new().write(true).create(true).truncate(have == 0).open(partial)?.set_len(size)?;
+            let _ = std::fs::remove_file(validator_path(partial));
+            Chunks::new(size, &validator, have, connections)
+        }
+    };
+    chunks.save(partial)?;
+
+    let name = file_name(url);
+    let count = chunks.ranges.len();
+    let done = AtomicU64::new(chunks.done());
+    let chunks = Mutex::new(chunks);
+    let stop = AtomicBool::new(false);
+    let results = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..count).map(|i| {
+            let (chunks, done, stop) = (&chunks, &done, &stop);
+            s.spawn(move || {
+                let res = with_retry("Downloading", || download_range(url, partial, i, chunks, done, stop, cancel));
+                if res.is_err() {
+                    stop.store(true, Ordering::Relaxed);
+                }
+                res
+            })
+        }).collect();
+
+        let mut speed = Throughput::new(THROUGHPUT_WINDOW);
+        while !workers.iter().all(|x| x.is_finished()) {
+            std::thread::sleep(PROGRESS_INTERVAL);
+            if cancel.is_some_and(|x| x.state() == crate::cancel::RunState::Paused) {
+                speed.reset();
+            }
+            let d = done.load(Ordering::Relaxed);
+            progress.emit(transfer_event(&name, d, size, speed.sample(d)));
+            let _ = chunks.lock().unwrap().save(partial);
+        }
+        workers.into_iter().map(|x| x.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    let chunks = chunks.into_inner().unwrap();
+    chunks.save(partial)?;
+    if let Some(e) = results.into_iter().find_map(|x| x.err()) {
+        return Err(e);
+    }
+    progress.emit(transfer_event(&name, size, size, None));
+
+    // Complete, so from here on it is an ordinary partial file that a single stream would find finished.
+    std::fs::remove_file(chunks_path(partial))?;
+    if let Some(v) = &validator {
+        std::fs::write(validator_path(partial), v)?;
+    }
+    Ok(hex::encode(hash_existing(partial)?.finalize()))
+}
+
+/// Downloads `url` into the named file `partial`, resuming with `Range` requests if the
+/// connection drops or a previous launcher session left a partial file behind. Large files,
+/// sized by `exp_size` or a range probe, are split across `download_connections` range requests.
+/// The caller is responsible for calling [`remove_partial`] once the file has been used.
+fn download_resumable(exp_size: Option<u64>, url: &str, partial: &Path, progress: &Progress, cancel: Option<&CancelToken>) -> Result<(LoadedFileSource, String), anyhow::Error> {
+    if let Some(p) = partial.parent() {
+        std::fs::create_dir_all(p)?;
+    }
+
+    let connections = download_connections();
+    if connections > 1 && exp_size.is_none_or(|x| x >= CHUNKED_MIN_SIZE)
+        && let Some((size, validator)) = probe_ranges(exp_size, url)?
+        && size >= CHUNKED_MIN_SIZE {
+        let digest = download_chunked(size, validator, url, partial, connections, progress, cancel)?;
+        return Ok((LoadedFileSource::OnDisk(std::fs::File::open(partial)?), digest));
+    }
+    if chunks_path(partial).exists() {
+        // Left by a chunked download, so the partial file has gaps and cannot be appended to.
+        remove_partial(partial)?;
+    }
+
+    let digest = with_retry("Downloading", || download_partial(exp_size, url, partial, progress, cancel))?;
+    Ok((LoadedFileSource::OnDisk(std::fs::File::open(partial)?), digest))
+}
+
+/// Downloads `url` from the start into memory or, past [`crate::files::memory_limit`], a temp file.
+fn download_whole(exp_size: Option<u64>, url: &str, progress: &Progress, cancel: Option<&CancelToken>) -> Result<(LoadedFileSource, String), NetError> {
+    let resp = send(&client()?, "Download", url)?;
+    let exp_size = exp_size.or(resp.content_length());
+    let mut hasher = Sha256::new();
+    if let Some(size) = exp_size.filter(|x| *x > crate::files::memory_limit()) {
+        crate::files::ensure_space(&std::env::temp_dir(), size).map_err(|e| NetError::Other(e.to_string()))?;
+    }
+
+    let mut sink = LoadingFileSource::new(exp_size)?;
+    download_to_buffer(0, exp_size.unwrap_or(0), resp, &mut sink, &mut hasher, &file_name(url), progress, cancel)?;
+    Ok((sink.finish()?, hex::encode(hasher.finalize())))
+}
+
+/// The last path segment of a download URL, for error messages.
+fn file_name(url: &str) -> String {
+    reqwest::Url::parse(url).ok()
+        .and_then(|u| u.path_segments()?.next_back().map(|x| x.to_owned()))
+        .unwrap_or_else(|| url.to_owned())
+}
+
+/// Downloads `url`, resuming into `partial` if given. When `sha256` is known, a download that does
+/// not match it is discarded and fetched again before being reported as corrupt.
+/// Pauses while `cancel` is paused. If it is cancelled, the partial download is removed.
+pub fn download_file(exp_size: Option<u64>, url: &str, partial: Option<&Path>, sha256: Option<&str>, progress: &Progress, cancel: Option<&CancelToken>)  -> Result<LoadedFileSource, anyhow::Error>{
+    let res = download_checked(exp_size, url, partial, sha256, progress, cancel);
+    if res.is_err() && let (Some(p), Some(c)) = (partial, cancel) && c.is_cancelled() {
+        remove_partial(p)?;
+    }
+    res
+}
+
+fn download_checked(exp_size: Option<u64>, url: &str, partial: Option<&Path>, sha256: Option<&str>, progress: &Progress, cancel: Option<&CancelToken>)  -> Result<LoadedFileSource, anyhow::Error>{
+    progress.emit(ProgressEvent::new(Phase::Downloading, Some(file_name(url)), 0, exp_size.unwrap_or(0)));
+
+    let mut attempt = 1;
+    loop {
+        let (file, digest) = match partial {
+            Some(p) => download_resumable(exp_size, url, p, progress, cancel)?,
+            None => with_retry("Downloading", || download_whole(exp_size, url, progress, cancel))?
+        };
 
-    path.push("bg.png");
+        let expected = match sha256 {
+            Some(e) if !e.trim().eq_ignore_ascii_case(&digest) => e.trim(),
+            _ => return Ok(file)
+        };
+
+        let err = NetError::Integrity { url: file_name(url), expected: expected.to_owned(), actual: digest };
+        if attempt >= INTEGRITY_ATTEMPTS {
+            return Err(err.into());
+        }
+        log::warn!("{}, downloading again", err);
+        drop(file);
+        if let Some(p) = partial {
+            remove_partial(p)?;
+        }
+        attempt += 1;
+    }
+}
+
+/// Where the last downloaded background is kept.
+fn image_path() -> Option<PathBuf> {
+    platform_dirs::AppDirs::new(Some("Procelio Launcher"), true).map(|x| x.config_dir.join("bg.png"))
+}
+
+/// The background saved by a previous [`load_image`], without going to the network.
+pub fn saved_image() -> Option<Vec<u8>> {
+    std::fs::read(image_path()?).ok()
+}
+
+pub fn load_image(cdn: &str, curr_name: String, image_name: String) -> Option<Vec<u8>> {
+    let path = image_path()?;
 
     let data = std::fs::read(&path).ok();
     if data.is_some() && curr_name == image_name {
         return Some(data.unwrap());
     }
 
-    let url = match get_image_url("nyc3", &image_name) {
+    let url = match get_image_url(cdn, &image_name) {
         Ok(a) => a,
         Err(_) => { return None; }
     };
 
-    let src = match download_file(None, &url, None) {
+    let src = match download_file(None, &url, None, None, &Progress::default(), None) {
         Ok(s) => s,
         Err(_) => { return None; }
     };
@@ -228,11 +1196,264 @@ pub fn load_image(curr_name: String, image_name: String) -> Option<Vec<u8>> {
 
    if let Some(parent_path) = path.parent() {
         if let Err(e) = std::fs::create_dir_all(parent_path) {
-            println!("Error creating directory {:?}: {:?}", parent_path, e);
+            log::warn!("Error creating directory {:?}: {:?}", parent_path, e);
         }
     }
 
     let result = std::fs::write(path, bytes.as_slice());
-    println!("{:?}", result);
+    log::debug!("{:?}", result);
     Some(bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+
+    /// Serves each connection on a local port on its own thread. `handler` gets the request line and
+    /// headers and writes the response. Returns the server's address.
+    fn serve(handler: impl Fn(&[String], &mut TcpStream) -> std::io::Result<()> + Send + Sync + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = std::sync::Arc::new(handler);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return; };
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let (mut request, mut line) = (Vec::new(), String::new());
+                    while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                        request.push(std::mem::take(&mut line));
+                    }
+                    let _ = handler(&request, &mut stream);
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    /// The value of header `name` in a request read by [`serve`].
+    fn request_header<'a>(request: &'a [String], name: &str) -> Option<&'a str> {
+        request.iter().find_map(|x| {
+            let (key, value) = x.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    /// Answers every request on a local port with `status` and `body`, and returns its address.
+    fn mock_server(status: &'static str, body: &'static str) -> String {
+        serve(move |_, stream| write!(stream, "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len()))
+    }
+
+    /// Serves `body` on a local port, answering `Range: bytes=a-b` requests with the matching part.
+    fn range_server(body: Vec<u8>) -> String {
+        serve(move |request, stream| {
+            let range = request_header(request, "range").and_then(|r| {
+                let (a, b) = r.strip_prefix("bytes=")?.split_once('-')?;
+                Some((a.parse::<usize>().ok()?, b.parse::<usize>().ok()?))
+            });
+            match range {
+                Some((a, b)) => write!(stream, "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {a}-{b}/{}\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len(), b + 1 - a)
+                    .and_then(|_| stream.write_all(&body[a..=b])),
+                None => write!(stream, "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len())
+                    .and_then(|_| stream.write_all(&body))
+            }
+        })
+    }
+
+    #[test]
+    fn test_stalled_body_is_transient() {
+        // Promises ten bytes, sends two, then goes quiet.
+        let server = serve(|_, stream| {
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n[]")?;
+            thread::sleep(Duration::from_secs(2));
+            Ok(())
+        });
+        let url = format!("{server}/config");
+
+        let client = blocking::Client::builder().timeout(Duration::from_millis(300)).build().unwrap();
+        let err: NetError = client.get(&url).send().unwrap().text().unwrap_err().into();
+        assert!(err.is_transient(), "{err:?}");
+    }
+
+    #[test]
+    fn test_get_json_since() {
+        // Answers "not modified" to anyone who already has version "v1".
+        let server = serve(|request, stream| match request_header(request, "if-none-match") == Some("\"v1\"") {
+            true => write!(stream, "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n"),
+            false => write!(stream, "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Sat, 17 Oct 2026 10:00:00 GMT\r\nContent-Length: 2\r\nConnection: close\r\n\r\n[]")
+        });
+        let url = format!("{server}/config");
+
+        let validator = match get_json_since::<Vec<u32>>("Test", &url, None).unwrap() {
+            Fetched::Modified(data, v) => {
+                assert!(data.is_empty());
+                v
+            }
+            Fetched::NotModified => panic!("nothing was cached")
+        };
+        assert_eq!(validator.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(validator.last_modified.as_deref(), Some("Sat, 17 Oct 2026 10:00:00 GMT"));
+        assert!(matches!(get_json_since::<Vec<u32>>("Test", &url, Some(&validator)).unwrap(), Fetched::NotModified));
+    }
+
+    #[test]
+    fn test_download_chunked() {
+        let body: Vec<u8> = (0..200_000u32).map(|x| (x % 251) as u8).collect();
+        let server = range_server(body.clone());
+        let url = format!("{server}/cdn/release.zip");
+        let dir = tempfile::tempdir().unwrap();
+        let partial = dir.path().join("release.part");
+
+        // The probe finds the size when the caller does not know it.
+        let (size, validator) = probe_ranges(None, &url).unwrap().unwrap();
+        assert_eq!(size, body.len() as u64);
+        assert_eq!(validator.as_deref(), Some("\"v1\""));
+
+        // A previous session got partway through the second range.
+        let mut chunks = Chunks::new(size, &validator, 0, 4);
+        std::fs::write(&partial, vec![0; body.len()]).unwrap();
+        let (start, _, _) = chunks.ranges[1];
+        chunks.ranges[1].2 = 1000;
+        let mut f = std::fs::OpenOptions::new().write(true).open(&partial).unwrap();
+        f.seek(std::io::SeekFrom::Start(start)).unwrap();
+        f.write_all(&body[start as usize..start as usize + 1000]).unwrap();
+        chunks.save(&partial).unwrap();
+        assert_eq!(Chunks::load(&partial, size, &validator).map(|x| x.done()), Some(1000));
+
+        let (progress, events) = Progress::channel();
+        let digest = download_chunked(size, validator, &url, &partial, 4, &progress, None).unwrap();
+        assert_eq!(digest, hex::encode(Sha256::digest(&body)));
+        assert_eq!(std::fs::read(&partial).unwrap(), body);
+        assert!(!chunks_path(&partial).exists());
+        assert_eq!(events.try_iter().last().map(|x| x.done), Some(body.len() as u64));
+    }
+
+    #[test]
+    fn test_download_chunked_continues_single_stream() {
+        let body: Vec<u8> = (0..200_000u32).map(|x| (x % 251) as u8).collect();
+        let server = range_server(body.clone());
+        let url = format!("{server}/cdn/release.zip");
+        let dir = tempfile::tempdir().unwrap();
+        let partial = dir.path().join("release.part");
+
+        // A single-stream download of the same version stopped partway. Its bytes are marked so
+        // the test can tell they were kept rather than fetched again.
+        std::fs::write(&partial, vec![7; 5000]).unwrap();
+        std::fs::write(validator_path(&partial), "\"v1\"").unwrap();
+
+        let (size, validator) = probe_ranges(None, &url).unwrap().unwrap();
+        download_chunked(size, validator, &url, &partial, 4, &Progress::default(), None).unwrap();
+        let data = std::fs::read(&partial).unwrap();
+        assert_eq!(data[..5000], [7; 5000]);
+        assert_eq!(data[5000..], body[5000..]);
+    }
+
+    #[test]
+    fn test_zip_listing() {
+        let zip = crate::backend::FakeBackend::zip(&[("Procelio.exe", &[1; 3000]), ("data/level.bin", &[2; 5000])]);
+        let server = range_server(zip.clone());
+        let listing = zip_listing(&format!("{server}/cdn/patch.zip")).unwrap().unwrap();
+        assert_eq!(listing.size, zip.len() as u64);
+        assert_eq!(listing.unpacked_size, 8000);
+        assert_eq!(listing.names, ["Procelio.exe", "data/level.bin"]);
+    }
+
+    #[test]
+    fn test_get_url_accepts_download_url() {
+        let server = mock_server("200 OK", "https://nyc3.cdn.example.com/prod/win/1.2.0.zip?X-Amz-Signature=abc\n");
+        let url = get_url("Release path", &format!("{server}/v1/paths/release")).unwrap();
+        assert_eq!(url, "https://nyc3.cdn.example.com/prod/win/1.2.0.zip?X-Amz-Signature=abc");
+    }
+
+    #[test]
+    fn test_get_url_rejects_error_status() {
+        let server = mock_server("404 Not Found", "https://looks.like/a/url");
+        let err = get_url("Release path for '1.2.0' (channel 'prod')", &format!("{server}/v1/paths/release")).unwrap_err();
+        assert!(matches!(&err, NetError::Status { status, .. } if *status == StatusCode::NOT_FOUND));
+        assert!(!err.is_transient());
+        assert!(err.to_string().contains("channel 'prod'"));
+    }
+
+    #[test]
+    fn test_get_url_rejects_error_page() {
+        let server = mock_server("200 OK", "<html><body>Internal Server Error</body></html>");
+        let err = get_url("Patch path", &format!("{server}/v1/paths/patch")).unwrap_err();
+        assert!(matches!(err, NetError::BadResponse(_)));
+        assert!(err.to_string().contains("Patch path"));
+    }
+
+    #[test]
+    fn test_get_url_rejects_non_http_scheme() {
+        let server = mock_server("200 OK", "file:///etc/passwd");
+        assert!(matches!(get_url("Image path", &format!("{server}/v1/paths/image")), Err(NetError::BadResponse(_))));
+    }
+
+    #[test]
+    fn test_download_file_checks_digest() {
+        let server = mock_server("200 OK", "hello");
+        let url = format!("{server}/cdn/release.zip");
+        let good = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let mut data = String::new();
+        download_file(None, &url, None, Some(good), &Progress::default(), None).unwrap().as_reader().read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello");
+
+        let bad = good.replace('2', "3");
+        let err = download_file(None, &url, None, Some(&bad), &Progress::default(), None).err().unwrap();
+        assert!(matches!(err.downcast_ref::<NetError>(), Some(NetError::Integrity { .. })));
+    }
+
+    #[test]
+    fn test_download_file_cancel_removes_partial() {
+        let server = mock_server("200 OK", "hello");
+        let dir = tempfile::tempdir().unwrap();
+        let partial = dir.path().join("release-1.2.0.part");
+        let cancel = CancelToken::new();
+        cancel.cancel();
+
+        let err = download_file(None, &format!("{server}/cdn/release.zip"), Some(&partial), None, &Progress::default(), Some(&cancel)).err().unwrap();
+        assert!(matches!(err.downcast_ref::<NetError>(), Some(NetError::Cancelled)));
+        assert!(!partial.exists());
+        assert!(!validator_path(&partial).exists());
+    }
+
+    #[test]
+    fn test_set_proxy_rejects_invalid_settings() {
+        let bad_url = ProxySettings { url: "not a url".to_owned(), ..Default::default() };
+        assert!(matches!(set_proxy(bad_url), Err(NetError::Other(_))));
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("ca.pem"), "not a certificate").unwrap();
+        for cert in [dir.path().join("missing.pem"), dir.path().join("ca.pem")] {
+            let bad_cert = ProxySettings { ca_certs: vec![cert], ..Default::default() };
+            assert!(matches!(set_proxy(bad_cert), Err(NetError::Other(_))));
+        }
+        assert_eq!(proxy(), ProxySettings::default());
+    }
+
+    #[test]
+    fn test_proxy_password_is_not_saved() {
+        let settings = ProxySettings { username: "player".to_owned(), password: "hunter2".to_owned(), ..Default::default() };
+        let json = serde_json::to_string(&settings).unwrap();
+        assert!(!json.contains("hunter2"));
+        assert_eq!(serde_json::from_str::<ProxySettings>(&json).unwrap().password, "");
+        assert!(!format!("{settings:?}").contains("hunter2"));
+    }
+
+    #[test]
+    fn test_backend_override() {
+        let args: Vec<String> = ["launcher", "--backend", "http://127.0.0.1:9630/"].iter().map(|x| x.to_string()).collect();
+        let o = BackendOverride::from_args(&args).unwrap();
+        let backend = Backend::from_override(&o).unwrap();
+        assert_eq!(backend.url, "http://127.0.0.1:9630");
+        assert!(!backend.production);
+        assert!(backend.cert.is_none());
+
+        let bad = BackendOverride { url: "releases.procelio.com".to_owned(), cert: None };
+        assert!(Backend::from_override(&bad).is_err());
+        // A remote server has to pin its certificate, or the killswitch could not be checked.
+        let unpinned = BackendOverride { url: "https://staging.example.com".to_owned(), cert: None };
+        assert!(Backend::from_override(&unpinned).is_err());
+    }
+}
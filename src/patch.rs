@@ -1,15 +1,24 @@
-use std::io::BufRead;
-use std::thread;
-use std::boxed::Box;
-use crate::json::{InstallManifest, OldInstallManifest};
+use std::io::{BufRead, Read};
+use crate::backend::ReleaseBackend;
+use crate::cancel::CancelToken;
+use crate::net::NetError;
+use crate::progress::{Part, Phase, Progress};
+use crate::json::{FileEntry, InstallManifest, OldInstallManifest, Patch, Release, VerifyReport};
 use std::io::Seek;
+use std::collections::HashSet;
+use sha2::{Digest, Sha256};
 
 #[derive(Clone)]
 pub struct PlayGameConfig {
-    pub cdn: String,
+    /// Download regions to use, best first.
+    pub cdns: Vec<String>,
     pub channel: String,
     pub latest_build: String,
     pub args: Vec<String>,
+    /// Set for the game on top of the launcher's own environment.
+    pub env: Vec<(String, String)>,
+    /// A command the game is run through, e.g. `gamemoderun %command%`. Empty runs it directly.
+    pub wrapper: String,
 }
 
 pub fn delete_old_launcher() -> Result<(), anyhow::Error> {
@@ -25,7 +34,7 @@ pub fn delete_old_launcher() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
-fn get_installed_version(install_dir: &std::path::PathBuf) -> Result<Option<InstallManifest>, anyhow::Error> {
+pub fn get_installed_version(install_dir: &std::path::PathBuf) -> Result<Option<InstallManifest>, anyhow::Error> {
     let mut path = install_dir.to_owned();
     path.push("manifest.json");
     if !path.is_file() {
@@ -38,14 +47,16 @@ fn get_installed_version(install_dir: &std::path::PathBuf) -> Result<Option<Inst
     manifest.or(old_manifest.map(|x|x.into())).map(|x|Some(x)).map_err(|x|x.into())
 }
 
-fn unzip_to<T: Seek + BufRead>(dir: std::path::PathBuf, reader: T, cb: Option<&dyn Fn(f32, String)>) -> Result<(), anyhow::Error>{
+/// Extracts `reader` into `dir`. If `only` is given, every other entry in the archive is skipped.
+fn unzip_to<T: Seek + BufRead>(dir: std::path::PathBuf, reader: T, only: Option<&HashSet<std::path::PathBuf>>, phase: Phase, progress: &Progress, cancel: &CancelToken) -> Result<(), anyhow::Error>{
     let mut strm = zip::ZipArchive::new(reader)?;
 
     let len = strm.len();
-    println!("unzip for {}", len);
+    log::debug!("unzip for {}", len);
     for i in 0..len {
+        cancel.checkpoint()?;
         let mut file = strm.by_index(i)?;
-        println!("Unzip {:?}", &file.enclosed_name());
+        log::debug!("Unzip {:?}", &file.enclosed_name());
 
         let name = match file.enclosed_name() {
             Some(a) => {
@@ -53,12 +64,12 @@ fn unzip_to<T: Seek + BufRead>(dir: std::path::PathBuf, reader: T, cb: Option<&d
             }
             None => { continue; }
         };
-        
-
-        if let Some(s) = cb {
-            s((i as f32) / (len as f32), format!("Extracting {} ({})", name.display(), file.size()));
+        if only.is_some_and(|x| !x.contains(&name)) {
+            continue;
         }
 
+        progress.step(phase, name.display().to_string(), i, len);
+
         let outpath = dir.join(name.components());
         if file.is_dir() {
             std::fs::create_dir_all(outpath)?;
@@ -76,37 +87,167 @@ fn unzip_to<T: Seek + BufRead>(dir: std::path::PathBuf, reader: T, cb: Option<&d
     Ok(())
 }
 
-fn download_fresh(config: PlayGameConfig, dir: &std::path::PathBuf, process: std::sync::Arc<std::sync::Mutex<(f32, String, Option<Box<anyhow::Error>>)>>) -> Result<Option<InstallManifest>, anyhow::Error> {
-    let path = crate::net::get_release_url(&config.cdn, &config.channel, &config.latest_build)?;
-    let file = crate::net::download_file(None, &path, Some(process.clone()))?;
+/// Bytes the archive takes once extracted, counting only the entries in `only` if given.
+/// Read from the zip's directory, so nothing is decompressed. Leaves `reader` back at the start.
+fn unpacked_size<T: Read + Seek>(reader: &mut T, only: Option<&HashSet<std::path::PathBuf>>) -> Result<u64, anyhow::Error> {
+    let mut zip = zip::ZipArchive::new(&mut *reader)?;
+    let mut size = 0;
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i)?;
+        if only.is_none_or(|x| file.enclosed_name().is_some_and(|n| x.contains(&n))) {
+            size += file.size();
+        }
+    }
+    drop(zip);
+    reader.rewind()?;
+    Ok(size)
+}
+
+/// Bytes of a `size` byte download not yet in `partial`.
+fn remaining(partial: &std::path::Path, size: u64) -> u64 {
+    size.saturating_sub(std::fs::metadata(partial).map_or(0, |x| x.len()))
+}
+
+/// Where an in-progress download is kept, so it can be resumed after a dropped connection or a launcher restart.
+fn partial_path(dir: &std::path::Path, kind: &str, name: &str) -> std::path::PathBuf {
+    dir.join(format!("{kind}-{name}.part"))
+}
+
+/// Runs `f` against each region in turn until one succeeds, so a CDN that drops out partway through
+/// a download does not fail the whole task. Cancelling and failures no other region can fix stop at once.
+fn with_regions<T>(cdns: &[String], mut f: impl FnMut(&str) -> Result<T, anyhow::Error>) -> Result<T, anyhow::Error> {
+    let mut res = Err(anyhow::Error::msg("No download regions available"));
+    for cdn in cdns {
+        res = f(cdn);
+        match &res {
+            Err(e) if matches!(e.downcast_ref(), Some(NetError::Transient(_) | NetError::Status { .. } | NetError::Integrity { .. })) => {
+                log::warn!("Download from {cdn} failed ({e}), trying the next region");
+            }
+            _ => return res
+        }
+    }
+    res
+}
+
+/// What the server says about release `name`. Not every server keeps release records, so a missing
+/// one is `None` and the release is downloaded without a size or digest to check.
+fn find_release(backend: &dyn ReleaseBackend, channel: &str, name: &str) -> Result<Option<Release>, anyhow::Error> {
+    match backend.release(channel, name) {
+        Ok(r) => Ok(Some(r)),
+        Err(e) if matches!(e.downcast_ref(), Some(NetError::BadResponse(_)))
+            || matches!(e.downcast_ref(), Some(NetError::Status { status, .. }) if *status == reqwest::StatusCode::NOT_FOUND) => {
+            log::warn!("No release info for {name} in {channel}, downloading it unchecked: {e:#}");
+            Ok(None)
+        },
+        Err(e) => Err(e)
+    }
+}
+
+/// Downloads release `config.latest_build` and extracts it into `target`. The partial download is kept in `dir`.
+fn download_fresh(backend: &dyn ReleaseBackend, config: PlayGameConfig, dir: &std::path::PathBuf, target: &std::path::PathBuf, release: Option<&Release>, progress: &Progress, cancel: &CancelToken) -> Result<Option<InstallManifest>, anyhow::Error> {
+    let name = &config.latest_build;
+    let partial = partial_path(dir, "release", name);
+    let size = release.map(|x| x.download_size);
+    if let Some(size) = size {
+        // The extracted files take at least as much room as the archive.
+        crate::files::ensure_space(dir, remaining(&partial, size) + size)?;
+    }
+    let file = with_regions(&config.cdns, |cdn| {
+        let path = backend.release_url(cdn, &config.channel, name)?;
+        if let Some(listing) = backend.zip_listing(&path)? {
+            // The rest of the archive and everything in it, known before any of it is fetched.
+            crate::files::ensure_space(dir, remaining(&partial, listing.size) + listing.unpacked_size)?;
+        }
+        backend.download(size, &path, Some(&partial), release.and_then(|x| x.sha256.as_deref()), progress, Some(cancel))
+    })?;
+
+    log::info!("File downloaded");
+    let mut reader = file.as_reader();
+    crate::files::ensure_space(target, unpacked_size(&mut reader, None)?)?;
+    unzip_to(target.to_owned(), reader, None, Phase::Extracting, progress, cancel)?;
+    crate::net::remove_partial(&partial)?;
+    Ok(get_installed_version(target)?)
+}
 
-    println!("File downloaded");
-    unzip_to(dir.to_owned(), file.as_reader(), Some(&|a, b| {
-        let mut lock = process.lock().unwrap();
-        lock.0 = a;
-        lock.1 = b;
-    }))?;
-    Ok(get_installed_version(dir)?)
+/// The files in `dir` that a patch listing `names` changes. Patching gives each a copy of its own first,
+/// so a staged file that is hard-linked to the install cannot change the install.
+fn patched_files<'a>(dir: &std::path::Path, names: impl Iterator<Item = &'a str>) -> Vec<std::path::PathBuf> {
+    names.map(std::path::Path::new)
+        .filter(|x| x.components().all(|c| matches!(c, std::path::Component::Normal(_))))
+        .map(|x| dir.join(x))
+        .filter(|x| x.is_file())
+        .collect()
 }
 
-fn patch_to<T: Seek + BufRead>(dir: std::path::PathBuf, reader: T, cb: Option<&dyn Fn(f32, String)>) -> Result<(), anyhow::Error> {
-    let mut zip = zip::read::ZipArchive::new(reader)?;
-    proceliotool::tools::patch::from_zip(dir.to_owned(),&mut zip, cb)
+/// Bytes needed to give each of `files` a copy of its own.
+fn copy_size<'a>(files: impl IntoIterator<Item = &'a std::path::PathBuf>) -> u64 {
+    files.into_iter().map(|x| x.metadata().map_or(0, |m| m.len())).sum()
 }
 
-fn apply_patch(config: PlayGameConfig, dir: &std::path::PathBuf, patch: String, size: u64, process: std::sync::Arc<std::sync::Mutex<(f32, String, Option<Box<anyhow::Error>>)>>) -> Result<Option<InstallManifest>, anyhow::Error> {
-    let path = crate::net::get_patch_url(&config.cdn, &config.channel, &patch)?;
-    println!("Download patch {:?}", &path);
-    let file = crate::net::download_file(Some(size), &path, Some(process.clone()))?;
+/// Room a patch route needs in `dir` before any of it is downloaded: the largest patch, everything the
+/// patches hold, and copies of the installed files they change. `None` if a patch cannot be listed up front.
+fn route_space(backend: &dyn ReleaseBackend, config: &PlayGameConfig, dir: &std::path::Path, route: &[Patch]) -> Result<Option<u64>, anyhow::Error> {
+    let mut unpacked = 0;
+    let mut changed = HashSet::new();
+    for patch in route {
+        let listing = with_regions(&config.cdns, |cdn| backend.zip_listing(&backend.patch_url(cdn, &config.channel, &patch.name)?))?;
+        let Some(listing) = listing else {
+            return Ok(None);
+        };
+        unpacked += listing.unpacked_size;
+        changed.extend(patched_files(dir, listing.names.iter().map(|x| x.as_str())));
+    }
+    let largest = route.iter().map(|x| remaining(&partial_path(dir, "patch", &x.name), x.download_size)).max().unwrap_or(0);
+    Ok(Some(largest + unpacked + copy_size(&changed)))
+}
+
+/// Applies a patch archive to `dir`. A patch cannot be stopped halfway, so pausing takes effect at the
+/// next progress update and cancelling once the patch is finished.
+fn patch_to(backend: &dyn ReleaseBackend, dir: &std::path::Path, reader: Box<dyn crate::files::BufReadSeek>, cb: Option<&dyn Fn(f32, String)>, cancel: &CancelToken) -> Result<(), anyhow::Error> {
+    cancel.checkpoint()?;
+    let cancelled = std::cell::Cell::new(false);
+    let cb = |a, b| {
+        if cancel.checkpoint().is_err() && !cancelled.replace(true) {
+            log::info!("Cancelling once the current patch is applied");
+        }
+        if let Some(cb) = cb {
+            cb(a, b);
+        }
+    };
+    backend.apply_patch(dir, reader, Some(&cb))?;
+    if cancelled.get() {
+        return Err(crate::cancel::Cancelled.into());
+    }
+    Ok(cancel.checkpoint()?)
+}
 
-    let dd = patch_to(dir.to_owned(), file.as_reader(), Some(&|a, b| {
-        let mut lock = process.lock().unwrap();
-        lock.0 = a;
-        lock.1 = format!("Patch {}: {}", patch, b);
-    }));
-    println!("{:?}", dd);
+/// Downloads a patch and applies it to `target`. The partial download is kept in `dir`.
+fn apply_patch(backend: &dyn ReleaseBackend, config: PlayGameConfig, dir: &std::path::PathBuf, target: &std::path::PathBuf, patch: &Patch, progress: &Progress, cancel: &CancelToken) -> Result<Option<InstallManifest>, anyhow::Error> {
+    let partial = partial_path(dir, "patch", &patch.name);
+    crate::files::ensure_space(dir, remaining(&partial, patch.download_size))?;
+    let file = with_regions(&config.cdns, |cdn| {
+        let path = backend.patch_url(cdn, &config.channel, &patch.name)?;
+        log::info!("Download patch {:?}", &path);
+        backend.download(Some(patch.download_size), &path, Some(&partial), patch.sha256.as_deref(), progress, Some(cancel))
+    })?;
+
+    let mut reader = file.as_reader();
+    let zip = zip::ZipArchive::new(&mut reader)?;
+    let changed = patched_files(target, zip.file_names());
+    drop(zip);
+    // A patch never adds more than everything in it, on top of the copies of the files it changes.
+    crate::files::ensure_space(target, unpacked_size(&mut reader, None)? + copy_size(&changed))?;
+    for path in &changed {
+        crate::files::unshare(path)?;
+    }
+    let dd = patch_to(backend, target, reader, Some(&|a, b| {
+        // proceliotool reports a fraction, so count in percent.
+        progress.step(Phase::Patching, format!("{}: {}", patch.name, b), (a * 100.) as usize, 100);
+    }), cancel);
+    log::debug!("{:?}", dd);
     let _ = dd?;
-    Ok(get_installed_version(dir)?)
+    crate::net::remove_partial(&partial)?;
+    Ok(get_installed_version(target)?)
 }
 
 fn make_executable<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<()> {
@@ -117,97 +258,320 @@ fn make_executable<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<()> {
 
         std::fs::set_permissions(&path, permissions)?;
 
-        println!("Set permissions for: {:?}", path.as_ref());
+        log::debug!("Set permissions for: {:?}", path.as_ref());
     }
     Ok(())
 }
 
-fn launch_game(config: PlayGameConfig, manifest: Option<InstallManifest>, dir: std::path::PathBuf, version_send: std::sync::mpsc::Sender<Result<InstallManifest, anyhow::Error>>) -> Result<Option<std::process::Child>, anyhow::Error> {
-    let manifest = match manifest {
-        Some(s) => s,
-        None => { return Err(anyhow::Error::msg("Unable to load launch manifest")); }
-    };
-    println!("Launch Game: {:?}", dir.join(&manifest.exec));
-    version_send.send(Ok(manifest.clone()))?;
-    thread::spawn(move || {
-        make_executable(dir.join(&manifest.exec)).unwrap();
+/// Builds the command that starts the installed game with `config.args` and `config.env`, through `config.wrapper` if set.
+pub fn game_command(config: &PlayGameConfig, manifest: &InstallManifest, dir: &std::path::Path) -> Result<std::process::Command, anyhow::Error> {
+    make_executable(dir.join(&manifest.exec))?;
 
-        let data = std::process::Command::new(dir.join(manifest.exec))
-            .current_dir(dir)
-            .args(config.args)
-            .output().unwrap();
-        println!("{:?}", data.status);
-    });
-    Ok(None)
+    let exec = dir.join(&manifest.exec).to_string_lossy().into_owned();
+    let argv = crate::game::wrap(&config.wrapper, &exec, &config.args)?;
+    let mut command = std::process::Command::new(&argv[0]);
+    command.current_dir(dir).args(&argv[1..]).envs(config.env.iter().map(|(k, v)| (k, v)));
+    Ok(command)
 }
 
+/// Starts the installed game with its output going to the game log. Use `RunningGame::wait` to learn how it exits.
+pub fn launch_game(config: &PlayGameConfig, manifest: &InstallManifest, dir: &std::path::Path) -> Result<crate::game::RunningGame, anyhow::Error> {
+    log::info!("Launch Game: {:?}", dir.join(&manifest.exec));
+    let command = game_command(config, manifest, dir)?;
+    crate::game::spawn(command, crate::game::log_path().as_deref())
+}
 
-pub fn play_clicked_internal(
-    dir: std::path::PathBuf,
-    config: PlayGameConfig,
-    process: std::sync::Arc<std::sync::Mutex<(f32, String, Option<Box<anyhow::Error>>)>>,
-    version_send: std::sync::mpsc::Sender<Result<InstallManifest, anyhow::Error>>) -> Result<Option<std::process::Child>, anyhow::Error> {
-    proceliotool::tools::patch::check_rollback(&dir)?;
+/// A directory next to the install, e.g. `Procelio.staging` for `Procelio`. Being on the same
+/// filesystem is what lets a finished update be swapped in with a rename.
+fn sibling_path(dir: &std::path::Path, suffix: &str) -> std::path::PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{suffix}"));
+    dir.with_file_name(name)
+}
 
-    let installed_version = match get_installed_version(&dir)? {
-        Some(s) => s,
-        None => {
-           return launch_game(config.clone(), download_fresh(config.clone(), &dir, process)?, dir, version_send);
+fn is_partial(path: &std::path::Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".part") || name.ends_with(".part.etag") || name.ends_with(".part.chunks")
+}
+
+/// Copies the install at `from` into `to` so patches can be applied without touching the original.
+/// Files are hard-linked where the filesystem allows it and copied otherwise, so the copy costs next to
+/// no space or time; [`unshare_patched`] then gives each file a patch changes its own data.
+fn copy_install(from: &std::path::Path, to: &std::path::Path, progress: &Progress, cancel: &CancelToken) -> Result<(), anyhow::Error> {
+    for entry in walkdir::WalkDir::new(from) {
+        cancel.checkpoint()?;
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(from)?;
+        if is_partial(rel) {
+            continue;
         }
-    };
+        let target = to.join(rel);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(target)?;
+        } else {
+            progress.step(Phase::Preparing, rel.display().to_string(), 0, 0);
+            if let Err(e) = std::fs::hard_link(entry.path(), &target) {
+                log::debug!("Unable to link {:?} ({e}), copying it", rel);
+                crate::files::ensure_space(to, entry.metadata()?.len())?;
+                std::fs::copy(entry.path(), target)?;
+            }
+        }
+    }
+    Ok(())
+}
 
-    let path = crate::net::get_update_path(&installed_version.channel, &config.channel, &installed_version.version)?;
+/// Whether `dir` is named for the game, so everything in it can be taken to be ours.
+fn is_dedicated(dir: &std::path::Path) -> bool {
+    dir.ends_with("Procelio")
+}
 
-    let manifest = match path {
-        crate::json::UpgradePath::NoChangesRequired => Some(installed_version),
-        crate::json::UpgradePath::FreshDownload(d) => {
-            uninstall_internal(&dir, process.clone())?;
-            println!("{:?}", &d);
-            download_fresh(config.clone(), &dir, process)?
-        },
-        crate::json::UpgradePath::PatchRoute(pr) => {
-            let mut m = None;
-            for p in pr {
-                m = apply_patch(config.clone(), &dir, p.name, p.download_size, process.clone())?;
-            }
-            m
-        },
-    };
+/// Removes a file or directory, whichever `path` is.
+fn remove_entry(path: &std::path::Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path)?.is_dir() {
+        true => std::fs::remove_dir_all(path),
+        false => std::fs::remove_file(path)
+    }
+}
 
-    launch_game(config, manifest, dir, version_send)
+/// Puts every entry of `backup` back into `dir`, replacing what is there, then removes `backup`.
+fn restore_backup(dir: &std::path::Path, backup: &std::path::Path) -> Result<(), anyhow::Error> {
+    std::fs::create_dir_all(dir)?;
+    for entry in std::fs::read_dir(backup)? {
+        let entry = entry?;
+        let target = dir.join(entry.file_name());
+        if target.symlink_metadata().is_ok() {
+            remove_entry(&target)?;
+        }
+        std::fs::rename(entry.path(), target)?;
+    }
+    std::fs::remove_dir(backup)?;
+    Ok(())
 }
 
+/// Finishes or undoes an update that was interrupted while being swapped in, and throws away any
+/// half-built staging directory.
+fn recover_staged(dir: &std::path::PathBuf) -> Result<(), anyhow::Error> {
+    let backup = sibling_path(dir, "old");
+    if backup.is_dir() {
+        if !dir.exists() {
+            log::info!("Restoring previous install from {:?}", backup.display());
+            std::fs::rename(&backup, dir)?;
+        } else if get_installed_version(dir)?.is_some() {
+            // The new manifest goes in last, so the swap got all the way through.
+            std::fs::remove_dir_all(&backup)?;
+        } else {
+            log::warn!("Update was interrupted while being swapped in, restoring previous files from {:?}", backup.display());
+            restore_backup(dir, &backup)?;
+        }
+    }
+    let staging = sibling_path(dir, "staging");
+    if staging.exists() {
+        std::fs::remove_dir_all(staging)?;
+    }
+    Ok(())
+}
+
+/// Checks a staged install is complete enough to launch before it replaces the current one.
+fn check_staged(staging: &std::path::Path, manifest: Option<InstallManifest>) -> Result<InstallManifest, anyhow::Error> {
+    let manifest = manifest.ok_or_else(|| anyhow::Error::msg("Update did not produce a launch manifest"))?;
+    if !staging.join(&manifest.exec).is_file() {
+        return Err(anyhow::anyhow!("Update is missing the game executable {:?}", manifest.exec));
+    }
+    Ok(manifest)
+}
+
+/// Replaces the install at `dir` with `staging`. The previous install is moved aside and only
+/// deleted once the new one is in place, so `recover_staged` can undo an interrupted swap.
+fn swap_in(dir: &std::path::PathBuf, staging: &std::path::PathBuf) -> Result<(), anyhow::Error> {
+    if !is_dedicated(dir) || get_installed_version(dir)?.is_none() {
+        return merge_in(dir, staging);
+    }
 
-pub fn play_clicked(
-    dir: std::path::PathBuf,
-    config: PlayGameConfig,
-    process: std::sync::Arc<std::sync::Mutex<(f32, String, Option<Box<anyhow::Error>>)>>,
-    send: std::sync::mpsc::Sender<Result<(), anyhow::Error>>,
-    version_send: std::sync::mpsc::Sender<Result<InstallManifest, anyhow::Error>>
-) {
-    thread::spawn(move || {
-        let res = play_clicked_internal(dir, config, process.clone(), version_send);
-        match res {
-            Err(e) => {
-                send.send(Err(e)).unwrap();
+    let backup = sibling_path(dir, "old");
+    if dir.exists() {
+        std::fs::rename(dir, &backup)?;
+    }
+    if let Err(e) = std::fs::rename(staging, dir) {
+        if backup.exists() {
+            std::fs::rename(&backup, dir)?;
+        }
+        return Err(e.into());
+    }
+    if backup.exists() {
+        std::fs::remove_dir_all(&backup)?;
+    }
+    Ok(())
+}
+
+/// Moves the entries of `staging` into `dir` one at a time, for a `dir` that may hold files that are not ours.
+/// Only the entries being replaced are moved aside, so files of an older version that the new one
+/// does not have are left behind. The manifest is moved aside first and the new one put in last,
+/// so a missing manifest tells `recover_staged` the swap was interrupted.
+fn merge_in(dir: &std::path::Path, staging: &std::path::Path) -> Result<(), anyhow::Error> {
+    let backup = sibling_path(dir, "old");
+    std::fs::create_dir_all(dir)?;
+    std::fs::create_dir_all(&backup)?;
+    let mut names = std::fs::read_dir(staging)?.map(|x| x.map(|e| e.file_name())).collect::<Result<Vec<_>, _>>()?;
+    names.sort_by_key(|x| x != "manifest.json");
+
+    let moved = (|| -> Result<(), anyhow::Error> {
+        for name in &names {
+            let target = dir.join(name);
+            if target.symlink_metadata().is_ok() {
+                std::fs::rename(target, backup.join(name))?;
             }
-            Ok(c) => {
-                if let Some(mut z) = c {
-                    z.wait().unwrap();
-                }
-                send.send(Ok(())).unwrap();
+        }
+        for name in names.iter().rev() {
+            std::fs::rename(staging.join(name), dir.join(name))?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = moved {
+        restore_backup(dir, &backup)?;
+        return Err(e);
+    }
+    std::fs::remove_dir(staging)?;
+    std::fs::remove_dir_all(&backup)?;
+    Ok(())
+}
+
+/// Brings the install at `dir` up to date with `config`, installing it from scratch if needed.
+/// The update is built in a staging directory next to `dir` and only swapped in once it is complete,
+/// so the previous install is left as it was if anything fails or `cancel` is cancelled.
+/// Returns the manifest of the resulting install.
+pub fn update_internal(
+    backend: &dyn ReleaseBackend,
+    dir: &std::path::PathBuf,
+    config: &PlayGameConfig,
+    progress: &Progress,
+    cancel: &CancelToken) -> Result<Option<InstallManifest>, anyhow::Error> {
+    recover_staged(dir)?;
+    proceliotool::tools::patch::check_rollback(dir)?;
+
+    let staging = sibling_path(dir, "staging");
+    let staged = match get_installed_version(dir)? {
+        None => find_release(backend, &config.channel, &config.latest_build)
+            .and_then(|r| download_fresh(backend, config.clone(), dir, &staging, r.as_ref(), progress, cancel)),
+        Some(installed_version) => match backend.upgrade_route(&installed_version.channel, &config.channel, &installed_version.version)? {
+            crate::json::UpgradePath::NoChangesRequired => return Ok(Some(installed_version)),
+            crate::json::UpgradePath::FreshDownload(d) => {
+                log::debug!("{:?}", &d);
+                let config = PlayGameConfig { latest_build: d.name.clone(), ..config.clone() };
+                download_fresh(backend, config, dir, &staging, Some(&d), progress, cancel)
+            },
+            crate::json::UpgradePath::PatchRoute(pr) => {
+                // Checked up front where the patches can be listed, and for one patch download at a time where not.
+                // The staged copy checks for itself in case it cannot link.
+                route_space(backend, config, dir, &pr)
+                    .map(|x| x.unwrap_or_else(|| pr.iter().map(|x| x.download_size).max().unwrap_or(0)))
+                    .and_then(|need| crate::files::ensure_space(dir, need))
+                    .and_then(|_| copy_install(dir, &staging, progress, cancel)).and_then(|_| {
+                    let mut m = None;
+                    let mut part = Part { index: 0, count: pr.len(), done: 0, total: pr.iter().map(|x| x.download_size).sum() };
+                    for p in pr {
+                        m = apply_patch(backend, config.clone(), dir, &staging, &p, &progress.part(part, p.download_size), cancel)?;
+                        part.index += 1;
+                        part.done += p.download_size;
+                    }
+                    Ok(m)
+                })
+            },
+        }
+    }.and_then(|m| check_staged(&staging, m));
+
+    match staged {
+        Ok(manifest) if !cancel.is_cancelled() => {
+            progress.emit(crate::progress::ProgressEvent::new(Phase::Finishing, None, 0, 0));
+            swap_in(dir, &staging)?;
+            Ok(Some(manifest))
+        },
+        res => {
+            if staging.exists() {
+                let _ = std::fs::remove_dir_all(&staging);
             }
+            // A cancel that arrives after the last step still keeps the previous install.
+            Err(res.err().unwrap_or_else(|| crate::cancel::Cancelled.into()))
         }
-    });
+    }
 }
 
+/// Turns a manifest path into one under `dir`, refusing anything that could escape it.
+fn manifest_path(dir: &std::path::Path, entry: &FileEntry) -> Result<std::path::PathBuf, anyhow::Error> {
+    let rel = std::path::PathBuf::from(&entry.path);
+    if !rel.components().all(|x| matches!(x, std::path::Component::Normal(_))) {
+        return Err(anyhow::anyhow!("File manifest contains unsafe path {:?}", entry.path));
+    }
+    Ok(dir.join(rel))
+}
 
+fn file_matches(path: &std::path::Path, entry: &FileEntry) -> Result<bool, anyhow::Error> {
+    match std::fs::metadata(path) {
+        Ok(m) if m.is_file() && m.len() == entry.size => {},
+        _ => return Ok(false)
+    }
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(entry.sha256.trim().eq_ignore_ascii_case(&hex::encode(hasher.finalize())))
+}
 
-fn uninstall_internal(dir: &std::path::PathBuf, process: std::sync::Arc<std::sync::Mutex<(f32, String, Option<Box<anyhow::Error>>)>>) -> Result<(), anyhow::Error> {
-    if !dir.ends_with("Procelio") {
+/// Compares every file of the installed release against the server's manifest, and re-extracts
+/// the ones that are missing or corrupted from a fresh copy of the release.
+pub fn verify_internal(backend: &dyn ReleaseBackend, dir: &std::path::PathBuf, cdns: &[String], progress: &Progress, cancel: &CancelToken) -> Result<VerifyReport, anyhow::Error> {
+    recover_staged(dir)?;
+    proceliotool::tools::patch::check_rollback(dir)?;
+    let installed = match get_installed_version(dir)? {
+        Some(s) => s,
+        None => { return Err(anyhow::anyhow!("Procelio is not installed at {:?}", dir.display())); }
+    };
+
+    let manifest = with_regions(cdns, |cdn| backend.file_manifest(cdn, &installed.channel, &installed.version))?;
+    let len = manifest.files.len();
+    let mut broken = Vec::new();
+    for (i, entry) in manifest.files.iter().enumerate() {
+        cancel.checkpoint()?;
+        progress.step(Phase::Verifying, entry.path.as_str(), i, len);
+        if !file_matches(&manifest_path(dir, entry)?, entry)? {
+            log::warn!("Verify: {} is missing or corrupted", entry.path);
+            broken.push(entry);
+        }
+    }
+
+    let mut report = VerifyReport { checked: len, repaired: Vec::new() };
+    if broken.is_empty() {
+        return Ok(report);
+    }
+
+    let only = broken.iter().map(|x| std::path::PathBuf::from(&x.path)).collect::<HashSet<_>>();
+    let release = find_release(backend, &installed.channel, &installed.version)?;
+    let partial = partial_path(dir, "release", &installed.version);
+    let size = release.as_ref().map(|x| x.download_size);
+    if let Some(size) = size {
+        crate::files::ensure_space(dir, remaining(&partial, size))?;
+    }
+    let file = with_regions(cdns, |cdn| {
+        let path = backend.release_url(cdn, &installed.channel, &installed.version)?;
+        backend.download(size, &path, Some(&partial), release.as_ref().and_then(|x| x.sha256.as_deref()), progress, Some(cancel))
+    })?;
+    let mut reader = file.as_reader();
+    crate::files::ensure_space(dir, unpacked_size(&mut reader, Some(&only))?)?;
+    unzip_to(dir.to_owned(), reader, Some(&only), Phase::Repairing, progress, cancel)?;
+    crate::net::remove_partial(&partial)?;
+
+    for entry in broken {
+        if !file_matches(&manifest_path(dir, entry)?, entry)? {
+            return Err(anyhow::anyhow!("Unable to repair {}: the release archive does not match the file manifest", entry.path));
+        }
+        report.repaired.push(entry.path.clone());
+    }
+    Ok(report)
+}
+
+pub fn uninstall_internal(dir: &std::path::PathBuf, progress: &Progress) -> Result<(), anyhow::Error> {
+    if !is_dedicated(dir) {
         let msg = format!("Cannot guarantee path '{:?}' only contains Procelio files. Please delete manually.", dir.display());
         return Err(anyhow::anyhow!(msg));
     }
+    recover_staged(dir)?;
 
     let version = get_installed_version(&dir)?;
     if let None = version {
@@ -215,14 +579,9 @@ fn uninstall_internal(dir: &std::path::PathBuf, process: std::sync::Arc<std::syn
     }
 
     let num = std::fs::read_dir(dir)?.count();
-    let mut i = 0;
-    for file in std::fs::read_dir(dir)? {
+    for (i, file) in std::fs::read_dir(dir)?.enumerate() {
         let f = file?;
-        let mut lock = process.lock().unwrap();
-        lock.0 = (i as f32) / (num as f32);
-        lock.1 = format!("Removing {}", f.path().display());
-        i += 1;
-        drop(lock);
+        progress.step(Phase::Uninstalling, f.path().display().to_string(), i, num);
         if f.path().is_dir() {
             std::fs::remove_dir_all(f.path())?;
         }
@@ -233,15 +592,191 @@ fn uninstall_internal(dir: &std::path::PathBuf, process: std::sync::Arc<std::syn
     Ok(())
 }
 
-pub fn uninstall(dir: std::path::PathBuf, process: std::sync::Arc<std::sync::Mutex<(f32, String, Option<Box<anyhow::Error>>)>>, send: std::sync::mpsc::Sender<Result<(), anyhow::Error>>) {
-    println!("Invoke uninstall");
-    thread::spawn(move || {
-        if let Err(e) = uninstall_internal(&dir, process.clone()) {
-            send.send(Err(anyhow::anyhow!(format!("Uninstallation failed: {:?}", &e)))).unwrap();
-            process.lock().unwrap().2 = Some(Box::new(e));
-            return;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_verify_detects_corrupt_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("game.dat"), b"hello").unwrap();
+        let entry = |path: &str, size| FileEntry {
+            path: path.to_owned(),
+            size,
+            sha256: "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_owned()
+        };
+
+        let good = entry("game.dat", 5);
+        assert!(file_matches(&manifest_path(dir.path(), &good).unwrap(), &good).unwrap());
+        let truncated = entry("game.dat", 4);
+        assert!(!file_matches(&manifest_path(dir.path(), &truncated).unwrap(), &truncated).unwrap());
+        let missing = entry("Data/missing.dat", 5);
+        assert!(!file_matches(&manifest_path(dir.path(), &missing).unwrap(), &missing).unwrap());
+        assert!(manifest_path(dir.path(), &entry("../escape.dat", 5)).is_err());
+    }
+
+    #[test]
+    fn test_verify_repairs_from_checked_release() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("Procelio");
+        let backend = crate::backend::FakeBackend::new(&["prod"], &["nyc3"]);
+        let manifest = br#"{"exec": "Procelio", "version": "1.0.0", "channel": "prod"}"#;
+        backend.add_release("nyc3", "prod", "1.0.0", crate::backend::FakeBackend::zip(&[("manifest.json", manifest), ("Procelio", b"hello")]));
+        backend.add_file_manifest("prod", "1.0.0", crate::json::FileManifest { files: vec![FileEntry {
+            path: "Procelio".to_owned(),
+            size: 5,
+            sha256: "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_owned()
+        }] });
+        let config = PlayGameConfig { cdns: vec!["nyc3".to_owned()], channel: "prod".to_owned(), latest_build: "1.0.0".to_owned(), args: Vec::new(), env: Vec::new(), wrapper: String::new() };
+        let (progress, cancel) = (Progress::default(), CancelToken::new());
+        update_internal(&backend, &dir, &config, &progress, &cancel).unwrap();
+        std::fs::write(dir.join("Procelio"), b"broken").unwrap();
+
+        // The release a repair comes from is checked like any other full download.
+        let good = backend.release("prod", "1.0.0").unwrap();
+        backend.set_release(Release { sha256: Some("0".repeat(64)), ..good.clone() });
+        let err = verify_internal(&backend, &dir, &config.cdns, &progress, &cancel).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(crate::net::NetError::Integrity { .. })), "{err:?}");
+        assert_eq!(std::fs::read(dir.join("Procelio")).unwrap(), b"broken");
+
+        backend.set_release(good);
+        let report = verify_internal(&backend, &dir, &config.cdns, &progress, &cancel).unwrap();
+        assert_eq!(report.repaired, vec!["Procelio"]);
+        assert_eq!(std::fs::read(dir.join("Procelio")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_failed_update_keeps_previous_install() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("Procelio");
+        let backend = crate::backend::FakeBackend::new(&["prod", "dev"], &["nyc3"]);
+        let manifest = |version: &str, channel: &str| format!(r#"{{"exec": "Procelio", "version": "{version}", "channel": "{channel}"}}"#);
+        backend.set_channel("prod", "1.0.0", &[]);
+        backend.add_release("nyc3", "prod", "1.0.0", crate::backend::FakeBackend::zip(&[("manifest.json", manifest("1.0.0", "prod").as_bytes()), ("Procelio", b"1.0.0")]));
+        // Missing its executable, so it must never replace a working install.
+        backend.add_release("nyc3", "dev", "1.1.0", crate::backend::FakeBackend::zip(&[("manifest.json", manifest("1.1.0", "dev").as_bytes())]));
+        let config = |channel: &str, latest: &str| PlayGameConfig { cdns: vec!["nyc3".to_owned()], channel: channel.to_owned(), latest_build: latest.to_owned(), args: Vec::new(), env: Vec::new(), wrapper: String::new() };
+        let progress = Progress::default();
+        let cancel = CancelToken::new();
+
+        update_internal(&backend, &dir, &config("prod", "1.0.0"), &progress, &cancel).unwrap();
+        backend.set_route("prod", "dev", "1.0.0", crate::json::UpgradePath::FreshDownload(Release {
+            channel: "dev".to_owned(),
+            platform: "linux".to_owned(),
+            name: "1.1.0".to_owned(),
+            download_size: 0,
+            title: String::new(),
+            description: String::new(),
+            changelog: String::new(),
+            sha256: None
+        }));
+        let cancelled = CancelToken::new();
+        cancelled.cancel();
+        let err = update_internal(&backend, &dir, &config("dev", "1.1.0"), &progress, &cancelled).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(crate::net::NetError::Cancelled)), "{err:?}");
+        assert!(update_internal(&backend, &dir, &config("dev", "1.1.0"), &progress, &cancel).is_err());
+        assert_eq!(get_installed_version(&dir).unwrap().unwrap().version, "1.0.0");
+        assert_eq!(std::fs::read(dir.join("Procelio")).unwrap(), b"1.0.0");
+        assert!(!sibling_path(&dir, "staging").exists());
+
+        // A swap interrupted after the old install was moved aside is undone on the next run.
+        std::fs::rename(&dir, sibling_path(&dir, "old")).unwrap();
+        std::fs::create_dir(sibling_path(&dir, "staging")).unwrap();
+        let m = update_internal(&backend, &dir, &config("prod", "1.0.0"), &progress, &cancel).unwrap().unwrap();
+        assert_eq!(m.version, "1.0.0");
+        assert!(!sibling_path(&dir, "old").exists());
+        assert!(!sibling_path(&dir, "staging").exists());
+    }
+
+    #[test]
+    fn test_swap_keeps_foreign_files() {
+        // Installed straight into a shared folder rather than one of its own.
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("Games");
+        std::fs::create_dir_all(dir.join("OtherGame")).unwrap();
+        std::fs::write(dir.join("OtherGame").join("save.dat"), b"keep").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"keep").unwrap();
+
+        let backend = crate::backend::FakeBackend::new(&["prod", "dev"], &["nyc3"]);
+        let manifest = |version: &str, channel: &str| format!(r#"{{"exec": "Procelio", "version": "{version}", "channel": "{channel}"}}"#);
+        backend.add_release("nyc3", "prod", "1.0.0", crate::backend::FakeBackend::zip(&[("manifest.json", manifest("1.0.0", "prod").as_bytes()), ("Procelio", b"1.0.0")]));
+        backend.add_release("nyc3", "dev", "1.1.0", crate::backend::FakeBackend::zip(&[("manifest.json", manifest("1.1.0", "dev").as_bytes()), ("Procelio", b"1.1.0")]));
+        let config = |channel: &str, latest: &str| PlayGameConfig { cdns: vec!["nyc3".to_owned()], channel: channel.to_owned(), latest_build: latest.to_owned(), args: Vec::new(), env: Vec::new(), wrapper: String::new() };
+        let (progress, cancel) = (Progress::default(), CancelToken::new());
+
+        update_internal(&backend, &dir, &config("prod", "1.0.0"), &progress, &cancel).unwrap();
+        let release = backend.release("dev", "1.1.0").unwrap();
+        backend.set_route("prod", "dev", "1.0.0", crate::json::UpgradePath::FreshDownload(release));
+        update_internal(&backend, &dir, &config("dev", "1.1.0"), &progress, &cancel).unwrap();
+
+        assert_eq!(get_installed_version(&dir).unwrap().unwrap().version, "1.1.0");
+        assert_eq!(std::fs::read(dir.join("Procelio")).unwrap(), b"1.1.0");
+        assert_eq!(std::fs::read(dir.join("notes.txt")).unwrap(), b"keep");
+        assert_eq!(std::fs::read(dir.join("OtherGame").join("save.dat")).unwrap(), b"keep");
+        assert!(!sibling_path(&dir, "old").exists());
+
+        // A swap interrupted after the old manifest was moved aside puts the previous files back.
+        let backup = sibling_path(&dir, "old");
+        std::fs::create_dir(&backup).unwrap();
+        std::fs::rename(dir.join("manifest.json"), backup.join("manifest.json")).unwrap();
+        recover_staged(&dir).unwrap();
+        assert_eq!(get_installed_version(&dir).unwrap().unwrap().version, "1.1.0");
+        assert!(!backup.exists());
+        assert_eq!(std::fs::read(dir.join("notes.txt")).unwrap(), b"keep");
+    }
+
+    #[test]
+    fn test_staged_copy_is_linked_until_patched() {
+        let root = tempfile::tempdir().unwrap();
+        let (dir, staging) = (root.path().join("Procelio"), root.path().join("Procelio.staging"));
+        std::fs::create_dir_all(dir.join("Data")).unwrap();
+        std::fs::write(dir.join("Data").join("level.dat"), b"old").unwrap();
+        std::fs::write(dir.join("Procelio"), b"exe").unwrap();
+        std::fs::write(dir.join("release-1.0.0.part"), b"partial").unwrap();
+
+        copy_install(&dir, &staging, &Progress::default(), &CancelToken::new()).unwrap();
+        assert!(!staging.join("release-1.0.0.part").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(std::fs::metadata(dir.join("Procelio")).unwrap().nlink(), 2);
         }
-        println!("Uninstall ok");
-        send.send(Ok(())).unwrap();
-    });
+
+        // A patcher that writes into the file it changes only touches the staged copy.
+        let changed = patched_files(&staging, ["Data/level.dat", "../escape.dat", "Data/new.dat"].into_iter());
+        assert_eq!(changed, [staging.join("Data").join("level.dat")]);
+        assert_eq!(copy_size(&changed), 3);
+        for path in &changed {
+            crate::files::unshare(path).unwrap();
+        }
+        std::fs::OpenOptions::new().write(true).open(staging.join("Data").join("level.dat")).unwrap().write_all(b"new").unwrap();
+        assert_eq!(std::fs::read(dir.join("Data").join("level.dat")).unwrap(), b"old");
+        assert_eq!(std::fs::read(staging.join("Procelio")).unwrap(), b"exe");
+    }
+
+    #[test]
+    fn test_cancel_during_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = crate::backend::FakeBackend::new(&["prod"], &["nyc3"]);
+        let patch = crate::backend::FakeBackend::zip(&[("a.dat", b"a"), ("b.dat", b"b")]);
+        let cancel = CancelToken::new();
+        let reader = crate::files::LoadedFileSource::InMemory(patch).as_reader();
+
+        // Cancelled partway through: the patch still finishes, then the update stops.
+        let err = patch_to(&backend, dir.path(), reader, Some(&|_, _| cancel.cancel()), &cancel).unwrap_err();
+        assert!(err.is::<crate::cancel::Cancelled>(), "{err:?}");
+        assert!(dir.path().join("b.dat").is_file());
+    }
+
+    #[test]
+    fn test_unpacked_size() {
+        let zip = crate::backend::FakeBackend::zip(&[("manifest.json", b"{}"), ("Data/game.dat", &[0; 1000])]);
+        let mut reader = std::io::Cursor::new(zip);
+        assert_eq!(unpacked_size(&mut reader, None).unwrap(), 1002);
+        let only = HashSet::from([std::path::PathBuf::from("Data/game.dat")]);
+        assert_eq!(unpacked_size(&mut reader, Some(&only)).unwrap(), 1000);
+        // Still readable from the start afterwards.
+        assert_eq!(zip::ZipArchive::new(reader).unwrap().len(), 2);
+    }
 }
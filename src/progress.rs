@@ -0,0 +1,263 @@
+//! Typed progress reports from downloads, unzipping and patching, for the window, the CLI and the logs.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Starting,
+    Downloading,
+    Extracting,
+    /// Copying the current install aside so patches can be applied to the copy.
+    Preparing,
+    Patching,
+    Verifying,
+    Repairing,
+    /// Swapping a finished update into place.
+    Finishing,
+    Uninstalling
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            Phase::Starting => "Starting",
+            Phase::Downloading => "Downloading",
+            Phase::Extracting => "Extracting",
+            Phase::Preparing => "Preparing",
+            Phase::Patching => "Patching",
+            Phase::Verifying => "Verifying",
+            Phase::Repairing => "Repairing",
+            Phase::Finishing => "Finishing update",
+            Phase::Uninstalling => "Uninstalling"
+        })
+    }
+}
+
+/// Where a step sits in a sequence of downloads, such as the patches of a `PatchRoute`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Part {
+    /// From 0.
+    pub index: usize,
+    pub count: usize,
+    /// Bytes across the whole sequence.
+    pub done: u64,
+    pub total: u64
+}
+
+impl Part {
+    pub fn fraction(&self) -> f32 {
+        match self.total {
+            0 => self.index as f32 / self.count.max(1) as f32,
+            t => (self.done as f64 / t as f64).min(1.) as f32
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressEvent {
+    pub phase: Phase,
+    /// The file being downloaded, extracted or checked.
+    pub file: Option<String>,
+    /// Bytes while downloading, files otherwise.
+    pub done: u64,
+    /// `0` when unknown.
+    pub total: u64,
+    /// Bytes per second, while downloading.
+    pub rate: Option<f64>,
+    pub eta: Option<Duration>,
+    pub part: Option<Part>
+}
+
+impl ProgressEvent {
+    pub fn new(phase: Phase, file: Option<String>, done: u64, total: u64) -> ProgressEvent {
+        ProgressEvent { phase, file, done, total, rate: None, eta: None, part: None }
+    }
+
+    /// How far along the phase is, from 0 to 1.
+    pub fn fraction(&self) -> f32 {
+        match self.total {
+            0 => 0.,
+            t => (self.done as f64 / t as f64).min(1.) as f32
+        }
+    }
+
+    /// Bytes, speed and time left while downloading, e.g. "412 MB / 2.9 GB – 18.4 MB/s – 2m 15s left".
+    pub fn transfer(&self) -> Option<String> {
+        if self.phase != Phase::Downloading {
+            return None;
+        }
+        let mut text = match self.total {
+            0 => format_bytes(self.done),
+            t => format!("{} / {}", format_bytes(self.done), format_bytes(t))
+        };
+        if let Some(rate) = self.rate {
+            text += &format!(" – {}/s", format_bytes(rate as u64));
+        }
+        if let Some(eta) = self.eta {
+            text += &format!(" – {} left", format_duration(eta));
+        }
+        Some(text)
+    }
+
+    /// How far along the whole sequence is, e.g. "Patch 2 of 5 – 1.2 GB / 2.9 GB".
+    pub fn overall(&self) -> Option<String> {
+        let part = self.part?;
+        let mut text = format!("Patch {} of {}", part.index + 1, part.count);
+        if part.total > 0 {
+            text += &format!(" – {} / {}", format_bytes(part.done), format_bytes(part.total));
+        }
+        Some(text)
+    }
+}
+
+/// Formats a byte count with one decimal below 100 of a unit, e.g. "2.9 GB" or "412 MB".
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+    match (unit, value < 100.) {
+        (0, _) | (_, false) => format!("{:.0} {}", value, UNITS[unit]),
+        _ => format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a time left to the nearest second, e.g. "2m 15s".
+pub fn format_duration(time: Duration) -> String {
+    let secs = time.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s}s"),
+        (h, m, _) => format!("{h}h {m}m")
+    }
+}
+
+/// Transfer speed averaged over the last few seconds, so it follows changes without jumping around.
+pub struct Throughput {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>
+}
+
+impl Throughput {
+    pub fn new(window: Duration) -> Throughput {
+        Throughput { window, samples: VecDeque::new() }
+    }
+
+    /// Records that `done` bytes have been transferred so far, and returns the speed in bytes per second.
+    pub fn sample(&mut self, done: u64) -> Option<f64> {
+        self.sample_at(Instant::now(), done)
+    }
+
+    fn sample_at(&mut self, now: Instant, done: u64) -> Option<f64> {
+        self.samples.push_back((now, done));
+        while self.samples.len() > 2 && self.samples.front().is_some_and(|x| now - x.0 > self.window) {
+            self.samples.pop_front();
+        }
+        let (first, bytes) = self.samples.front()?;
+        let secs = (now - *first).as_secs_f64();
+        (secs > 0.).then(|| done.saturating_sub(*bytes) as f64 / secs)
+    }
+
+    /// Forgets the speed so far, e.g. after a pause.
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+}
+
+impl std::fmt::Display for ProgressEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{} {}", self.phase, file),
+            None => write!(f, "{}", self.phase)
+        }
+    }
+}
+
+/// Where a task sends its `ProgressEvent`s. The default drops them.
+#[derive(Clone, Default)]
+pub struct Progress {
+    sink: Option<Arc<dyn Fn(ProgressEvent) + Send + Sync>>
+}
+
+impl Progress {
+    pub fn new(sink: impl Fn(ProgressEvent) + Send + Sync + 'static) -> Progress {
+        Progress { sink: Some(Arc::new(sink)) }
+    }
+
+    pub fn channel() -> (Progress, Receiver<ProgressEvent>) {
+        let (send, recv) = std::sync::mpsc::channel();
+        (Progress::new(move |e| { let _ = send.send(e); }), recv)
+    }
+
+    pub fn emit(&self, event: ProgressEvent) {
+        if let Some(sink) = &self.sink {
+            sink(event);
+        }
+    }
+
+    /// A `Progress` for step `part.index` of a sequence, which starts `part.done` bytes in and downloads `size` bytes.
+    /// Its events carry the progress across the whole sequence in `part`.
+    pub fn part(&self, part: Part, size: u64) -> Progress {
+        let inner = self.clone();
+        Progress::new(move |mut e| {
+            let done = match e.phase {
+                Phase::Downloading | Phase::Starting => e.done.min(size),
+                _ => size
+            };
+            e.part = Some(Part { done: part.done + done, ..part });
+            inner.emit(e);
+        })
+    }
+
+    /// Reports step `done` of `total` in a phase that counts files rather than bytes.
+    pub fn step(&self, phase: Phase, file: impl Into<String>, done: usize, total: usize) {
+        self.emit(ProgressEvent::new(phase, Some(file.into()), done as u64, total as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_text() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(412 * 1024 * 1024), "412 MB");
+        assert_eq!(format_bytes(2_900_000_000), "2.7 GB");
+        assert_eq!(format_duration(Duration::from_secs(135)), "2m 15s");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h 2m");
+
+        let mut event = ProgressEvent::new(Phase::Downloading, Some("1.2.0".to_owned()), 412 * 1024 * 1024, 3 * 1024 * 1024 * 1024);
+        event.rate = Some(18.4 * 1024. * 1024.);
+        event.eta = Some(Duration::from_secs(135));
+        assert_eq!(event.transfer().unwrap(), "412 MB / 3.0 GB – 18.4 MB/s – 2m 15s left");
+        assert_eq!(ProgressEvent::new(Phase::Patching, None, 1, 2).transfer(), None);
+    }
+
+    #[test]
+    fn test_throughput_averages_recent_samples() {
+        let start = Instant::now();
+        let mut t = Throughput::new(Duration::from_secs(5));
+        assert_eq!(t.sample_at(start, 0), None);
+        assert_eq!(t.sample_at(start + Duration::from_secs(1), 1000), Some(1000.));
+        // A stall older than the window no longer drags the speed down.
+        t.sample_at(start + Duration::from_secs(10), 1000);
+        assert_eq!(t.sample_at(start + Duration::from_secs(12), 5000), Some(2000.));
+    }
+
+    #[test]
+    fn test_part_tracks_the_whole_route() {
+        let (progress, recv) = Progress::channel();
+        let part = progress.part(Part { index: 1, count: 2, done: 100, total: 300 }, 200);
+        part.emit(ProgressEvent::new(Phase::Downloading, None, 50, 200));
+        part.step(Phase::Patching, "x", 0, 100);
+        let events: Vec<_> = recv.try_iter().map(|x| x.part.unwrap().done).collect();
+        assert_eq!(events, vec![150, 300]);
+    }
+}
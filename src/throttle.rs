@@ -0,0 +1,136 @@
+//! Caps how fast game downloads may go, so clicking Play does not take over a shared connection.
+
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use chrono::Timelike;
+use serde::{Deserialize, Serialize};
+use crate::cancel::CancelToken;
+
+/// A different limit for part of the day, e.g. office hours.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledLimit {
+    /// Local hour the limit starts, from 0 to 23.
+    pub from: u32,
+    /// Local hour the limit ends. Before `from` means the limit runs past midnight, and equal to it all day.
+    pub to: u32,
+    /// Bytes per second. `0` means unlimited.
+    pub rate: u64
+}
+
+impl ScheduledLimit {
+    pub fn contains(&self, hour: u32) -> bool {
+        match self.from.cmp(&self.to) {
+            std::cmp::Ordering::Less => (self.from..self.to).contains(&hour),
+            std::cmp::Ordering::Equal => true,
+            std::cmp::Ordering::Greater => hour >= self.from || hour < self.to
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthLimit {
+    /// Bytes per second outside any scheduled limit. `0` means unlimited.
+    pub rate: u64,
+    /// The first entry covering the current hour wins over `rate`.
+    pub schedule: Vec<ScheduledLimit>
+}
+
+impl BandwidthLimit {
+    /// Bytes per second allowed at local `hour`, or `None` for no limit.
+    pub fn rate_at(&self, hour: u32) -> Option<u64> {
+        let rate = self.schedule.iter().find(|x| x.contains(hour)).map_or(self.rate, |x| x.rate);
+        (rate > 0).then_some(rate)
+    }
+}
+
+/// Bytes that may be sent right away, refilled at the limit and capped at a second's worth.
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+impl Bucket {
+    /// Takes `bytes` out of the bucket, and returns how long to wait before they are paid for.
+    fn take(&mut self, rate: u64, bytes: u64, now: Instant) -> Duration {
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        self.updated = now;
+        match self.tokens < 0. {
+            true => Duration::from_secs_f64(-self.tokens / rate),
+            false => Duration::ZERO
+        }
+    }
+}
+
+static LIMIT: RwLock<Option<BandwidthLimit>> = RwLock::new(None);
+static BUCKET: Mutex<Option<Bucket>> = Mutex::new(None);
+
+pub fn limit() -> BandwidthLimit {
+    LIMIT.read().unwrap().clone().unwrap_or_default()
+}
+
+/// Replaces the limit applied to every following download.
+pub fn set_limit(limit: BandwidthLimit) {
+    *LIMIT.write().unwrap() = Some(limit);
+}
+
+/// Waits until `bytes` more may be downloaded under the current limit. Every download shares one bucket.
+pub fn throttle(bytes: u64, cancel: Option<&CancelToken>) {
+    let rate = match limit().rate_at(chrono::Local::now().hour()) {
+        Some(r) => r,
+        None => {
+            *BUCKET.lock().unwrap() = None;
+            return;
+        }
+    };
+    let now = Instant::now();
+    let wait = BUCKET.lock().unwrap()
+        .get_or_insert_with(|| Bucket { tokens: rate as f64, updated: now })
+        .take(rate, bytes, now);
+
+    // Sleep in slices so a cancel is not held up by a slow limit.
+    let until = now + wait;
+    while Instant::now() < until && !cancel.is_some_and(|x| x.is_cancelled()) {
+        std::thread::sleep(until.saturating_duration_since(Instant::now()).min(Duration::from_millis(100)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_at() {
+        let limit = BandwidthLimit {
+            rate: 1000,
+            schedule: vec![
+                ScheduledLimit { from: 9, to: 17, rate: 500 },
+                ScheduledLimit { from: 22, to: 6, rate: 0 }
+            ]
+        };
+        assert_eq!(limit.rate_at(8), Some(1000));
+        assert_eq!(limit.rate_at(9), Some(500));
+        assert_eq!(limit.rate_at(17), Some(1000));
+        assert_eq!(limit.rate_at(23), None);
+        assert_eq!(limit.rate_at(3), None);
+
+        // The same start and end hour covers the whole day.
+        let all_day = BandwidthLimit { schedule: vec![ScheduledLimit { from: 12, to: 12, rate: 250 }], ..limit };
+        assert_eq!(all_day.rate_at(11), Some(250));
+        assert_eq!(all_day.rate_at(12), Some(250));
+    }
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket { tokens: 1000., updated: start };
+        // A second's worth goes through at once, then the rest has to wait.
+        assert_eq!(bucket.take(1000, 1000, start), Duration::ZERO);
+        assert_eq!(bucket.take(1000, 500, start), Duration::from_millis(500));
+        // Idle time refills the bucket, but never past a second's worth.
+        assert_eq!(bucket.take(1000, 1000, start + Duration::from_secs(10)), Duration::ZERO);
+        assert_eq!(bucket.take(1000, 250, start + Duration::from_secs(10)), Duration::from_millis(250));
+    }
+}